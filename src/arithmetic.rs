@@ -0,0 +1,515 @@
+use anyhow::anyhow;
+use crate::errors::CrateResult;
+use crate::helpers::find_subscript_end;
+use crate::variables::{ split_subscript, Variables };
+
+// Integer arithmetic of `$(( ))`, `(( ))` and `let`, with the C operators,
+// their precedence and shell variables as operands. Values are 64 bit and
+// wrap around on overflow like in bash.
+pub fn evaluate(expression: &str, vars: &mut Variables) -> CrateResult<i64> {
+    evaluate_nested(expression, vars, 0)
+}
+
+// variables holding expressions are evaluated too, up to this depth
+const MAX_DEPTH: usize = 64;
+
+fn evaluate_nested(expression: &str, vars: &mut Variables, depth: usize) -> CrateResult<i64> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("{}: expression recursion level exceeded", expression));
+    }
+    let tokens = tokenize(expression)?;
+    // an empty expression is 0
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut parser = Parser { tokens, pos: 0, expression };
+    let expr = parser.comma()?;
+    if parser.peek().is_some() {
+        return Err(parser.syntax_error());
+    }
+    Evaluator { vars, depth, expression }.eval(&expr)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// longest operators first so that `<<=` is not read as `<` and `<=`
+const OPERATORS: [&str; 39] = [
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "|", "^", "!",
+    "~", "?", ":", "=", ",", "(", ")",
+];
+
+fn tokenize(expression: &str) -> CrateResult<Vec<Token>> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch.is_whitespace() {
+            i += 1;
+        } else if ch.is_ascii_digit() {
+            let len = chars[i..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '#' | '_' | '@'))
+                .count();
+            let literal: String = chars[i..i + len].iter().collect();
+            tokens.push(Token::Number(parse_number(&literal)?));
+            i += len;
+        } else if ch.is_ascii_alphabetic() || ch == '_' {
+            let len = chars[i..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                .count();
+            // an array element `name[subscript]`, the subscript an expression itself
+            let len = match chars.get(i + len) {
+                Some('[') => find_subscript_end(&chars, i + len).map_or(len, |end| end + 1 - i),
+                _ => len,
+            };
+            tokens.push(Token::Name(chars[i..i + len].iter().collect()));
+            i += len;
+        } else {
+            let rest: String = chars[i..].iter().collect();
+            let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) else {
+                return Err(anyhow!("{}: syntax error: invalid arithmetic operator", expression));
+            };
+            tokens.push(Token::Op(op));
+            i += op.len();
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Decimal, octal with a leading 0, hexadecimal with 0x, or `base#digits`
+fn parse_number(literal: &str) -> CrateResult<i64> {
+    let invalid = || anyhow!("{}: value too great for base", literal);
+    let (base, digits) = if let Some((base, digits)) = literal.split_once('#') {
+        let base: u32 = base.parse().map_err(|_| invalid())?;
+        if !(2..=64).contains(&base) {
+            return Err(anyhow!("{}: invalid arithmetic base", literal));
+        }
+        (base, digits)
+    } else if let Some(hex) = literal.strip_prefix("0x").or(literal.strip_prefix("0X")) {
+        (16, hex)
+    } else if literal.len() > 1 && literal.starts_with('0') {
+        (8, &literal[1..])
+    } else {
+        (10, literal)
+    };
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+    let mut value: i64 = 0;
+    for ch in digits.chars() {
+        // bases above 36 tell lower and upper case letters apart
+        let digit = match ch {
+            '0'..='9' => (ch as u32) - ('0' as u32),
+            'a'..='z' => (ch as u32) - ('a' as u32) + 10,
+            'A'..='Z' if base <= 36 => (ch as u32) - ('A' as u32) + 10,
+            'A'..='Z' => (ch as u32) - ('A' as u32) + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => u32::MAX,
+        };
+        if digit >= base {
+            return Err(invalid());
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(i64),
+    Variable(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    // `=` or one of the compound assignments like `+=`
+    Assign(&'static str, String, Box<Expr>),
+    // `++x` and `--x` give the new value, `x++` and `x--` the old one
+    Increment {
+        name: String,
+        delta: i64,
+        prefix: bool,
+    },
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    expression: &'a str,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn syntax_error(&self) -> anyhow::Error {
+        let rest: Vec<String> = self.tokens[self.pos..]
+            .iter()
+            .map(|token| {
+                match token {
+                    Token::Number(n) => n.to_string(),
+                    Token::Name(name) => name.clone(),
+                    Token::Op(op) => op.to_string(),
+                }
+            })
+            .collect();
+        anyhow!(
+            "{}: syntax error in expression (error token is \"{}\")",
+            self.expression.trim(),
+            rest.join(" ")
+        )
+    }
+
+    fn expect(&mut self, op: &str) -> CrateResult<()> {
+        match self.peek() {
+            Some(Token::Op(found)) if *found == op => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(_) => Err(self.syntax_error()),
+            None => Err(anyhow!("{}: syntax error: `{}' expected", self.expression.trim(), op)),
+        }
+    }
+
+    fn comma(&mut self) -> CrateResult<Expr> {
+        let mut expr = self.assignment()?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            expr = Expr::Binary(",", Box::new(expr), Box::new(self.assignment()?));
+        }
+        Ok(expr)
+    }
+
+    fn assignment(&mut self) -> CrateResult<Expr> {
+        if let (Some(Token::Name(name)), Some(Token::Op(op))) = (
+            self.tokens.get(self.pos),
+            self.tokens.get(self.pos + 1),
+        ) {
+            if op.ends_with('=') && !matches!(*op, "==" | "!=" | "<=" | ">=") {
+                let (name, op) = (name.clone(), *op);
+                self.pos += 2;
+                return Ok(Expr::Assign(op, name, Box::new(self.assignment()?)));
+            }
+        }
+        self.conditional()
+    }
+
+    fn conditional(&mut self) -> CrateResult<Expr> {
+        let condition = self.binary(1)?;
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then = self.comma()?;
+        self.expect(":")?;
+        let otherwise = self.assignment()?;
+        Ok(Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    // Binary operators from `||` (1) up to `**` (11)
+    fn binary(&mut self, min_precedence: u8) -> CrateResult<Expr> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.peek_op() {
+            let precedence = match op {
+                "||" => 1,
+                "&&" => 2,
+                "|" => 3,
+                "^" => 4,
+                "&" => 5,
+                "==" | "!=" => 6,
+                "<" | ">" | "<=" | ">=" => 7,
+                "<<" | ">>" => 8,
+                "+" | "-" => 9,
+                "*" | "/" | "%" => 10,
+                "**" => 11,
+                _ => 0,
+            };
+            if precedence == 0 || precedence < min_precedence {
+                break;
+            }
+            self.pos += 1;
+            // `**` is the only right associative one
+            let next = if op == "**" { precedence } else { precedence + 1 };
+            let right = self.binary(next)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> CrateResult<Expr> {
+        match self.peek_op() {
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                match self.peek().cloned() {
+                    Some(Token::Name(name)) => {
+                        self.pos += 1;
+                        let delta = if op == "++" { 1 } else { -1 };
+                        Ok(Expr::Increment { name, delta, prefix: true })
+                    }
+                    // `--5` is just minus minus five
+                    _ => {
+                        let sign = if op == "++" { "+" } else { "-" };
+                        let operand = Expr::Unary(sign, Box::new(self.unary()?));
+                        Ok(Expr::Unary(sign, Box::new(operand)))
+                    }
+                }
+            }
+            Some(op @ ("-" | "+" | "!" | "~")) => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> CrateResult<Expr> {
+        match self.peek().cloned() {
+            Some(Token::Number(n)) => {
+                self.pos += 1;
+                Ok(Expr::Number(n))
+            }
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                match self.peek_op() {
+                    Some(op @ ("++" | "--")) => {
+                        self.pos += 1;
+                        let delta = if op == "++" { 1 } else { -1 };
+                        Ok(Expr::Increment { name, delta, prefix: false })
+                    }
+                    _ => Ok(Expr::Variable(name)),
+                }
+            }
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let expr = self.comma()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(_) => Err(self.syntax_error()),
+            None => Err(anyhow!("{}: syntax error: operand expected", self.expression.trim())),
+        }
+    }
+}
+
+struct Evaluator<'a> {
+    vars: &'a mut Variables,
+    depth: usize,
+    expression: &'a str,
+}
+
+impl Evaluator<'_> {
+    fn eval(&mut self, expr: &Expr) -> CrateResult<i64> {
+        match expr {
+            Expr::Number(n) => Ok(*n),
+            Expr::Variable(name) => self.variable(name),
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                Ok(match *op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                })
+            }
+            // the right side is only evaluated when it decides the result
+            Expr::Binary("&&", left, right) => {
+                Ok((self.eval(left)? != 0 && self.eval(right)? != 0) as i64)
+            }
+            Expr::Binary("||", left, right) => {
+                Ok((self.eval(left)? != 0 || self.eval(right)? != 0) as i64)
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                self.apply(op, left, right)
+            }
+            Expr::Assign(op, name, value) => {
+                let value = self.eval(value)?;
+                let value = match op.strip_suffix('=') {
+                    Some("") | None => value,
+                    Some(op) => {
+                        let current = self.variable(name)?;
+                        self.apply(op, current, value)?
+                    }
+                };
+                self.assign(name, value)?;
+                Ok(value)
+            }
+            Expr::Increment { name, delta, prefix } => {
+                let old = self.variable(name)?;
+                let new = old.wrapping_add(*delta);
+                self.assign(name, new)?;
+                Ok(if *prefix { new } else { old })
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.eval(condition)? != 0 { self.eval(then) } else { self.eval(otherwise) }
+            }
+        }
+    }
+
+    // Unset and empty variables are 0, other values are expressions themselves
+    fn variable(&mut self, name: &str) -> CrateResult<i64> {
+        let value = match split_subscript(name) {
+            Some((name, subscript)) => {
+                let subscript = self.vars.subscript(name, subscript)?;
+                self.vars.element(name, &subscript).unwrap_or_default()
+            }
+            None => self.vars.get(name).unwrap_or_default(),
+        };
+        let value = value.trim().to_string();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        if let Ok(n) = value.parse::<i64>() {
+            return Ok(n);
+        }
+        evaluate_nested(&value, self.vars, self.depth + 1)
+    }
+
+    fn assign(&mut self, name: &str, value: i64) -> CrateResult<()> {
+        match split_subscript(name) {
+            Some((name, subscript)) => {
+                let subscript = self.vars.subscript(name, subscript)?;
+                self.vars.set_element(name, &subscript, value.to_string())
+            }
+            None => {
+                self.vars.set(name, value.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    fn apply(&self, op: &str, left: i64, right: i64) -> CrateResult<i64> {
+        apply(op, left, right).map_err(|err| anyhow!("{}: {}", self.expression.trim(), err))
+    }
+}
+
+fn apply(op: &str, left: i64, right: i64) -> CrateResult<i64> {
+    Ok(match op {
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left.wrapping_mul(right),
+        "/" | "%" if right == 0 => {
+            return Err(anyhow!("division by 0"));
+        }
+        "/" => left.wrapping_div(right),
+        "%" => left.wrapping_rem(right),
+        "**" if right < 0 => {
+            return Err(anyhow!("exponent less than 0"));
+        }
+        "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+        "<<" => left.wrapping_shl(right as u32),
+        ">>" => left.wrapping_shr(right as u32),
+        "<" => (left < right) as i64,
+        ">" => (left > right) as i64,
+        "<=" => (left <= right) as i64,
+        ">=" => (left >= right) as i64,
+        "==" => (left == right) as i64,
+        "!=" => (left != right) as i64,
+        "&" => left & right,
+        "|" => left | right,
+        "^" => left ^ right,
+        "," => right,
+        _ => {
+            return Err(anyhow!("{}: unknown operator", op));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> i64 {
+        evaluate(expression, &mut Variables::default()).unwrap()
+    }
+
+    fn error(expression: &str) -> String {
+        evaluate(expression, &mut Variables::default()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("7 - 3 - 2"), 2);
+        assert_eq!(eval("1 << 2 + 1"), 8);
+        assert_eq!(eval("5 & 3 | 8 ^ 1"), 9);
+        assert_eq!(eval("1 || 0 && 0"), 1);
+        assert_eq!(eval("-7 / 2"), -3);
+        assert_eq!(eval("-7 % 3"), -1);
+    }
+
+    #[test]
+    fn right_associative() {
+        assert_eq!(eval("2 ** 3 ** 2"), 512);
+        // the unary minus binds tighter than `**`
+        assert_eq!(eval("-2 ** 2"), 4);
+        assert_eq!(eval("1 ? 2 : 3 ? 4 : 5"), 2);
+        assert_eq!(eval("0 ? 2 : 0 ? 4 : 5"), 5);
+    }
+
+    #[test]
+    fn assignments() {
+        let mut vars = Variables::default();
+        assert_eq!(evaluate("x = 3, x += 4, x * 2", &mut vars).unwrap(), 14);
+        assert_eq!(vars.get("x"), Some("7"));
+        assert_eq!(evaluate("x++ + ++x", &mut vars).unwrap(), 16);
+        assert_eq!(vars.get("x"), Some("9"));
+    }
+
+    #[test]
+    fn overflow_wraps() {
+        assert_eq!(eval("9223372036854775807 + 1"), i64::MIN);
+        assert_eq!(eval("-9223372036854775807 - 2"), i64::MAX);
+        assert_eq!(eval("9223372036854775807 * 2"), -2);
+        assert_eq!(eval("2 ** 64"), 0);
+    }
+
+    #[test]
+    fn bases() {
+        assert_eq!(eval("16#ff"), 255);
+        assert_eq!(eval("2#1011"), 11);
+        assert_eq!(eval("8#17"), 15);
+        assert_eq!(eval("017"), 15);
+        assert_eq!(eval("0x1F"), 31);
+        assert_eq!(eval("36#z"), 35);
+        assert_eq!(eval("64#@"), 62);
+        assert_eq!(eval("64#_"), 63);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("1 / 0"), "1 / 0: division by 0");
+        assert_eq!(error("2#3"), "2#3: value too great for base");
+        assert_eq!(error("65#1"), "65#1: invalid arithmetic base");
+        assert_eq!(error("1 +"), "1 +: syntax error: operand expected");
+    }
+
+    #[test]
+    fn array_elements() {
+        let mut vars = Variables::default();
+        assert_eq!(evaluate("a[1 + 1] = 5, a[2] * 2", &mut vars).unwrap(), 10);
+        assert_eq!(vars.values("a"), ["5"]);
+        assert_eq!(evaluate("a[-1]++, a[2]", &mut vars).unwrap(), 6);
+        assert_eq!(evaluate("a[5]", &mut vars).unwrap(), 0);
+    }
+}
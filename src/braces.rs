@@ -0,0 +1,212 @@
+use crate::helpers::{
+    find_closing_backtick,
+    find_closing_double_quote,
+    find_closing_quote,
+    find_parameter_end,
+    find_substitution_end,
+};
+
+// Bash brace expansion of a raw word, done before any other expansion:
+// `a{b,c}d` gives `abd` and `acd`, `{1..10}`, `{01..10..2}` and `{a..z}` are
+// sequences. Braces may nest, quoted or escaped ones are literal, like those
+// of `${...}`, and a brace pair without a comma or a sequence stays as is.
+pub fn expand_braces(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    expand_from(&chars, 0)
+}
+
+// Expand the first brace expression at or after `start`, then the ones in
+// each of the resulting words
+fn expand_from(chars: &[char], start: usize) -> Vec<String> {
+    let mut i = start;
+
+    while i < chars.len() {
+        if chars[i] == '{' {
+            let alternatives = find_brace_end(chars, i).and_then(|end| {
+                Some((end, alternatives(&chars[i + 1..end])?))
+            });
+            if let Some((end, alternatives)) = alternatives {
+                let mut words = vec![];
+                for alternative in alternatives {
+                    let mut word = chars[..i].to_vec();
+                    word.extend(alternative.chars());
+                    word.extend(&chars[end + 1..]);
+                    words.extend(expand_from(&word, i));
+                }
+                return words;
+            }
+        } else if let Some(end) = skip_quoted(chars, i) {
+            i = end;
+        }
+        i += 1;
+    }
+
+    vec![chars.iter().collect()]
+}
+
+// The index of the last character of the quoted part, escape or substitution
+// starting at `i`, if one does
+fn skip_quoted(chars: &[char], i: usize) -> Option<usize> {
+    match (chars[i], chars.get(i + 1)) {
+        ('\\', Some(_)) => Some(i + 1),
+        ('\'', _) => find_closing_quote(chars, i, '\''),
+        ('"', _) => find_closing_double_quote(chars, i),
+        ('`', _) => find_closing_backtick(chars, i),
+        ('$', Some('(')) => find_substitution_end(chars, i),
+        ('$', Some('{')) => find_parameter_end(chars, i),
+        _ => None,
+    }
+}
+
+fn find_brace_end(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = open + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            '{' => {
+                depth += 1;
+            }
+            '}' if depth == 0 => {
+                return Some(i);
+            }
+            '}' => {
+                depth -= 1;
+            }
+            _ => {
+                if let Some(end) = skip_quoted(chars, i) {
+                    i = end;
+                }
+            }
+        }
+        i += 1;
+    }
+
+    None
+}
+
+// The words between the braces: the parts separated by top level commas, or
+// the elements of a sequence
+fn alternatives(body: &[char]) -> Option<Vec<String>> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i < body.len() {
+        match body[i] {
+            ',' if depth == 0 => parts.push(std::mem::take(&mut current)),
+            ch @ ('{' | '}') => {
+                depth += if ch == '{' { 1 } else { -1 };
+                current.push(ch);
+            }
+            ch => {
+                let end = skip_quoted(body, i).unwrap_or(i);
+                current.push(ch);
+                current.extend(&body[i + 1..=end]);
+                i = end;
+            }
+        }
+        i += 1;
+    }
+
+    if parts.is_empty() {
+        return sequence(&current);
+    }
+    parts.push(current);
+    Some(parts)
+}
+
+// `x..y` or `x..y..step` with integers or single letters
+fn sequence(body: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = body.split("..").collect();
+    let step = match parts.len() {
+        2 => 1,
+        3 => parts[2].parse::<i64>().ok()?.unsigned_abs().max(1) as i64,
+        _ => {
+            return None;
+        }
+    };
+    let (from, to) = (parts[0], parts[1]);
+
+    if let (Ok(start), Ok(end)) = (from.parse::<i64>(), to.parse::<i64>()) {
+        // a leading zero on either end pads all the numbers to the same width
+        let padded = |n: &str| {
+            let digits = n.trim_start_matches(['-', '+']);
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = if padded(from) || padded(to) { from.len().max(to.len()) } else { 0 };
+        return Some(
+            range(start, end, step)
+                .map(|n| format!("{:0width$}", n, width = width))
+                .collect()
+        );
+    }
+
+    let letter = |text: &str| {
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Some(c as i64),
+            _ => None,
+        }
+    };
+    let (start, end) = (letter(from)?, letter(to)?);
+    Some(
+        range(start, end, step)
+            .map(|c| ((c as u8) as char).to_string())
+            .collect()
+    )
+}
+
+// From `start` to `end` included, counting down when `end` is smaller
+fn range(start: i64, end: i64, step: i64) -> impl Iterator<Item = i64> {
+    let count = (start.abs_diff(end) / (step as u64)) as i64;
+    let step = if end < start { -step } else { step };
+    (0..=count).map(move |n| start + n * step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comma_lists() {
+        assert_eq!(expand_braces("a{b,c{d,e}}f"), ["abf", "acdf", "acef"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("{a,,b}"), ["a", "", "b"]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(expand_braces("{1..5}"), ["1", "2", "3", "4", "5"]);
+        assert_eq!(expand_braces("{5..1}"), ["5", "4", "3", "2", "1"]);
+        assert_eq!(expand_braces("{-2..2}"), ["-2", "-1", "0", "1", "2"]);
+        assert_eq!(expand_braces("{z..w}"), ["z", "y", "x", "w"]);
+    }
+
+    #[test]
+    fn sequence_steps() {
+        assert_eq!(expand_braces("{a..e..2}"), ["a", "c", "e"]);
+        // the sign of the step does not matter
+        assert_eq!(expand_braces("{1..10..-4}"), ["1", "5", "9"]);
+        assert_eq!(expand_braces("{10..1..4}"), ["10", "6", "2"]);
+    }
+
+    #[test]
+    fn zero_padding() {
+        assert_eq!(expand_braces("{01..10..3}"), ["01", "04", "07", "10"]);
+        assert_eq!(expand_braces("{001..3}"), ["001", "002", "003"]);
+        assert_eq!(expand_braces("{-01..1}"), ["-01", "000", "001"]);
+    }
+
+    #[test]
+    fn left_alone() {
+        assert_eq!(expand_braces("{a}"), ["{a}"]);
+        assert_eq!(expand_braces("{}"), ["{}"]);
+        assert_eq!(expand_braces("x{1..3"), ["x{1..3"]);
+        assert_eq!(expand_braces("{1..b}"), ["{1..b}"]);
+        assert_eq!(expand_braces("\\{a,b}"), ["\\{a,b}"]);
+        assert_eq!(expand_braces("\"{a,b}\""), ["\"{a,b}\""]);
+        assert_eq!(expand_braces("{a,${x},b}"), ["a", "${x}", "b"]);
+    }
+}
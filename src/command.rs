@@ -1,4 +1,8 @@
-use anyhow::{ anyhow, Ok };
+use anyhow::Ok;
+use std::path::{ Path, PathBuf };
+use crate::errors::ShellError;
+use crate::helpers::{ find_in_path, RedirectKind };
+use crate::variables::split_assignment;
 
 #[derive(Debug, PartialEq)]
 pub enum Command {
@@ -11,7 +15,25 @@ pub enum Command {
     Rm(Rm),
     Mv(Vec<String>),
     Mkdir(Vec<String>),
-    Exit,
+    Exit(Option<i32>),
+    Jobs,
+    Fg(Option<String>),
+    Bg(Option<String>),
+    Export(Vec<String>),
+    Declare(Vec<String>),
+    Unset(Vec<String>),
+    Set(Vec<String>),
+    Shift(Option<String>),
+    Break(Option<String>),
+    // `:` does nothing and succeeds, as in `while :; do ... done`
+    Colon,
+    Continue(Option<String>),
+    Shopt(Vec<String>),
+    Let(Vec<String>),
+    // the expression of `(( ))`, expanded when evaluated
+    Arithmetic(String),
+    Env(Env),
+    External(External),
 }
 
 #[derive(Debug, PartialEq)]
@@ -28,6 +50,223 @@ pub struct Rm {
     pub dirs: Vec<String>,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct External {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+// `env [NAME=value]... [program [args]...]`
+#[derive(Debug, PartialEq)]
+pub struct Env {
+    pub assignments: Vec<(String, String)>,
+    pub command: Option<External>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Redirect {
+    pub fd: u32,
+    pub kind: RedirectKind,
+    pub target: String,
+}
+
+// One stage of a pipeline: its raw words and the redirections in written order
+#[derive(Debug, Default, PartialEq)]
+pub struct SimpleCommand {
+    pub words: Vec<String>,
+    pub redirects: Vec<Redirect>,
+}
+
+// A pipeline stage: a simple command, or a compound one with the redirections
+// written after its end
+#[derive(Debug, PartialEq)]
+pub enum Stage {
+    Simple(SimpleCommand),
+    Compound(Compound, Vec<Redirect>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Compound {
+    If(If),
+    While(While),
+    For(For),
+    ArithmeticFor(ArithmeticFor),
+    Case(Case),
+}
+
+// `if list; then list; [elif list; then list;]... [else list;] fi`
+#[derive(Debug, PartialEq)]
+pub struct If {
+    // each condition with the list run when it succeeds, `elif` ones included
+    pub branches: Vec<(CommandList, CommandList)>,
+    pub otherwise: Option<CommandList>,
+}
+
+// `while list; do list; done`, or `until` when the condition must fail
+#[derive(Debug, PartialEq)]
+pub struct While {
+    pub condition: CommandList,
+    pub body: CommandList,
+    pub until: bool,
+}
+
+// `for name [in word...]; do list; done`, without `in` the loop goes over the
+// positional parameters
+#[derive(Debug, PartialEq)]
+pub struct For {
+    pub name: String,
+    pub words: Option<Vec<String>>,
+    pub body: CommandList,
+}
+
+// `for ((init; condition; step)); do list; done`
+#[derive(Debug, PartialEq)]
+pub struct ArithmeticFor {
+    pub init: String,
+    pub condition: String,
+    pub step: String,
+    pub body: CommandList,
+}
+
+// `case word in [(]pattern[|pattern]...) list;; ... esac`
+#[derive(Debug, PartialEq)]
+pub struct Case {
+    pub word: String,
+    pub items: Vec<CaseItem>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CaseItem {
+    pub patterns: Vec<String>,
+    pub body: CommandList,
+    pub end: CaseEnd,
+}
+
+// What follows the list of the item that matched
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaseEnd {
+    // `;;` ends the case command
+    Break,
+    // `;&` runs the list of the next item too
+    FallThrough,
+    // `;;&` tries the patterns of the next items
+    Continue,
+}
+
+// A chain of commands connected with `|`
+#[derive(Debug, PartialEq)]
+pub struct Pipeline {
+    pub commands: Vec<Stage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+// Pipelines joined with `&&` and `||`, evaluated from left to right. A
+// trailing `&` runs it in the background.
+#[derive(Debug, PartialEq)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+    pub background: bool,
+}
+
+// The and-or chains of an input, separated by `;` or newlines
+#[derive(Debug, Default, PartialEq)]
+pub struct CommandList {
+    pub items: Vec<AndOr>,
+}
+
+// The text shown for a job, rebuilt from the raw words
+impl std::fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stages: Vec<String> = self.commands
+            .iter()
+            .map(|stage| {
+                let (mut parts, redirects) = match stage {
+                    Stage::Simple(command) => (command.words.clone(), &command.redirects),
+                    Stage::Compound(compound, redirects) => (vec![compound.to_string()], redirects),
+                };
+                for redirect in redirects {
+                    match redirect.kind {
+                        RedirectKind::HereDoc | RedirectKind::HereDocQuoted =>
+                            parts.push(redirect.kind.to_string()),
+                        _ => parts.push(format!("{}{}", redirect.kind, redirect.target)),
+                    }
+                }
+                parts.join(" ")
+            })
+            .collect();
+        write!(f, "{}", stages.join(" | "))
+    }
+}
+
+impl std::fmt::Display for Compound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compound::If(clause) => {
+                for (idx, (condition, body)) in clause.branches.iter().enumerate() {
+                    let keyword = if idx == 0 { "if" } else { "elif" };
+                    write!(f, "{} {}; then {}; ", keyword, condition, body)?;
+                }
+                if let Some(otherwise) = &clause.otherwise {
+                    write!(f, "else {}; ", otherwise)?;
+                }
+                write!(f, "fi")
+            }
+            Compound::While(clause) => {
+                let keyword = if clause.until { "until" } else { "while" };
+                write!(f, "{} {}; do {}; done", keyword, clause.condition, clause.body)
+            }
+            Compound::For(clause) => {
+                write!(f, "for {}", clause.name)?;
+                if let Some(words) = &clause.words {
+                    write!(f, " in {}", words.join(" "))?;
+                }
+                write!(f, "; do {}; done", clause.body)
+            }
+            Compound::ArithmeticFor(clause) => {
+                let ArithmeticFor { init, condition, step, body } = clause;
+                write!(f, "for (({}; {}; {})); do {}; done", init, condition, step, body)
+            }
+            Compound::Case(clause) => {
+                write!(f, "case {} in ", clause.word)?;
+                for item in &clause.items {
+                    let end = match item.end {
+                        CaseEnd::Break => ";;",
+                        CaseEnd::FallThrough => ";&",
+                        CaseEnd::Continue => ";;&",
+                    };
+                    write!(f, "{}) {}{} ", item.patterns.join(" | "), item.body, end)?;
+                }
+                write!(f, "esac")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for CommandList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, and_or) in self.items.iter().enumerate() {
+            if idx > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", and_or.first)?;
+            for (connector, pipeline) in &and_or.rest {
+                let operator = if *connector == Connector::And { "&&" } else { "||" };
+                write!(f, " {} {}", operator, pipeline)?;
+            }
+            if and_or.background {
+                write!(f, " &")?;
+            }
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
 impl Ls {
     fn new() -> Self {
         Self {
@@ -45,21 +284,79 @@ impl Rm {
     }
 }
 
+impl External {
+    fn new(input: &[String]) -> Self {
+        Self {
+            program: input[0].to_string(),
+            args: input[1..].to_vec(),
+        }
+    }
+
+    // Find the program in `path`, the directories of the shell's $PATH
+    pub fn resolve(&self, path: Option<&str>) -> Result<PathBuf, anyhow::Error> {
+        match find_in_path(&self.program, path) {
+            Some(path) => Ok(path),
+            None if self.program.contains('/') && Path::new(&self.program).exists() =>
+                Err(ShellError::PermissionDenied(format!("permission denied: {}", self.program)).into()),
+            None => Err(ShellError::NotFound(format!("command <{}> not found", self.program)).into()),
+        }
+    }
+}
+
+// The expression of an arithmetic command word `((expression))`
+pub fn arithmetic_command(word: &str) -> Option<&str> {
+    word.strip_prefix("((").and_then(|word| word.strip_suffix("))"))
+}
+
 impl TryFrom<Vec<String>> for Command {
     type Error = anyhow::Error;
 
     fn try_from(input: Vec<String>) -> Result<Self, Self::Error> {
+        if let Some(expression) = arithmetic_command(&input[0]) {
+            if input.len() > 1 {
+                return Err(
+                    ShellError::Usage(
+                        format!("syntax error near unexpected token `{}'", input[1])
+                    ).into()
+                );
+            }
+            return Ok(Self::Arithmetic(expression.to_string()));
+        }
         match input[0].to_lowercase().as_str() {
-            "exit" => Ok(Self::Exit),
+            "exit" =>
+                match input.len() {
+                    1 => Ok(Self::Exit(None)),
+                    2 =>
+                        match input[1].parse::<i32>() {
+                            std::result::Result::Ok(code) => Ok(Self::Exit(Some(code & 0xff))),
+                            Err(_) =>
+                                Err(
+                                    ShellError::Usage(
+                                        format!("exit: {}: numeric argument required", input[1])
+                                    ).into()
+                                ),
+                        }
+                    _ => Err(ShellError::Usage("exit: too many arguments".into()).into()),
+                }
 
             "pwd" => {
                 return Ok(Self::Pwd);
             }
 
+            "jobs" => Ok(Self::Jobs),
+
+            "fg" | "bg" => if input.len() > 2 {
+                Err(ShellError::Usage(format!("{}: too many arguments", input[0])).into())
+            } else if input[0].eq_ignore_ascii_case("fg") {
+                Ok(Self::Fg(input.get(1).cloned()))
+            } else {
+                Ok(Self::Bg(input.get(1).cloned()))
+            }
+
             "cd" => if input.len() > 2 {
-                return Err(anyhow!("cd requires just one argument"));
+                return Err(ShellError::Usage("cd requires just one argument".into()).into());
             } else {
-                return Ok(Self::Cd(input[1].to_string()));
+                return Ok(Self::Cd(input.get(1).cloned().unwrap_or_default()));
             }
 
             "ls" => {
@@ -79,7 +376,9 @@ impl TryFrom<Vec<String>> for Command {
                                         result.is_listing = true;
                                     }
                                     _ => {
-                                        return Err(anyhow!("invalid option -{ch}"));
+                                        return Err(
+                                            ShellError::Usage(format!("invalid option -{ch}")).into()
+                                        );
                                     }
                                 }
                             }
@@ -95,14 +394,13 @@ impl TryFrom<Vec<String>> for Command {
             }
 
             "echo" => if input.len() < 2 {
-                return Err(anyhow!("echo requires an argument"));
+                return Err(ShellError::Usage("echo requires an argument".into()).into());
             } else {
                 return Ok(Self::Echo(input[1..].join(" ")));
             }
 
-            "cat" => if input.len() < 2 {
-                return Err(anyhow!("cat requires an argument"));
-            } else {
+            "cat" => {
+                // without arguments cat copies its standard input
                 return Ok(
                     Self::Cat(
                         input[1..]
@@ -114,7 +412,9 @@ impl TryFrom<Vec<String>> for Command {
             }
 
             "cp" => if input.len() != 3 {
-                return Err(anyhow!("cp requires two arguments: source & target"));
+                return Err(
+                    ShellError::Usage("cp requires two arguments: source & target".into()).into()
+                );
             } else {
                 return Ok(
                     Self::Cp(
@@ -127,7 +427,7 @@ impl TryFrom<Vec<String>> for Command {
             }
 
             "rm" => if input.len() < 2 {
-                return Err(anyhow!("rm requires at least one argument"));
+                return Err(ShellError::Usage("rm requires at least one argument".into()).into());
             } else {
                 match input[1].as_str() {
                     "-r" => if input.len() > 2 {
@@ -143,10 +443,14 @@ impl TryFrom<Vec<String>> for Command {
                             )
                         );
                     } else {
-                        return Err(anyhow!("missing a path"));
+                        return Err(ShellError::Usage("missing a path".into()).into());
                     }
                     v if v.chars().nth(0) == Some('-') => {
-                        return Err(anyhow!("invalid option <{v}>, expected options: -r"));
+                        return Err(
+                            ShellError::Usage(
+                                format!("invalid option <{v}>, expected options: -r")
+                            ).into()
+                        );
                     }
                     _ => {
                         return Ok(
@@ -166,7 +470,9 @@ impl TryFrom<Vec<String>> for Command {
 
             "mv" => if input.len() < 3 {
                 return Err(
-                    anyhow!("mv requires at least two arguments: ccxsource(s) and destination")
+                    ShellError::Usage(
+                        "mv requires at least two arguments: ccxsource(s) and destination".into()
+                    ).into()
                 );
             } else {
                 return Ok(
@@ -180,7 +486,7 @@ impl TryFrom<Vec<String>> for Command {
             }
 
             "mkdir" => if input.len() < 2 {
-                return Err(anyhow!("mkdir requires at least one argument"));
+                return Err(ShellError::Usage("mkdir requires at least one argument".into()).into());
             } else {
                 return Ok(
                     Self::Mkdir(
@@ -191,7 +497,52 @@ impl TryFrom<Vec<String>> for Command {
                     )
                 );
             }
-            y => Err(anyhow!(format!("command <{}> not found", y))),
+            "export" => Ok(Self::Export(input[1..].to_vec())),
+
+            "declare" | "typeset" => Ok(Self::Declare(input[1..].to_vec())),
+
+            "unset" => Ok(Self::Unset(input[1..].to_vec())),
+
+            "set" => Ok(Self::Set(input[1..].to_vec())),
+
+            "shift" => if input.len() > 2 {
+                Err(ShellError::Usage("shift: too many arguments".into()).into())
+            } else {
+                Ok(Self::Shift(input.get(1).cloned()))
+            }
+
+            ":" => Ok(Self::Colon),
+
+            "break" | "continue" => if input.len() > 2 {
+                Err(ShellError::Usage(format!("{}: too many arguments", input[0])).into())
+            } else if input[0].eq_ignore_ascii_case("break") {
+                Ok(Self::Break(input.get(1).cloned()))
+            } else {
+                Ok(Self::Continue(input.get(1).cloned()))
+            }
+
+            "shopt" => Ok(Self::Shopt(input[1..].to_vec())),
+
+            "let" => if input.len() < 2 {
+                Err(ShellError::Usage("let: expression expected".into()).into())
+            } else {
+                Ok(Self::Let(input[1..].to_vec()))
+            }
+
+            "env" => {
+                let mut env = Env { assignments: vec![], command: None };
+                let mut rest = &input[1..];
+                while let Some((name, value)) = rest.first().and_then(|w| split_assignment(w)) {
+                    env.assignments.push((name.to_string(), value.to_string()));
+                    rest = &rest[1..];
+                }
+                if !rest.is_empty() {
+                    env.command = Some(External::new(rest));
+                }
+                Ok(Self::Env(env))
+            }
+
+            _ => Ok(Self::External(External::new(&input))),
         }
     }
 }
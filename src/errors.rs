@@ -1 +1,52 @@
-pub type CrateResult<T> = anyhow::Result<T>; 
\ No newline at end of file
+pub type CrateResult<T> = anyhow::Result<T>;
+
+// Errors that map to a specific exit status, everything else exits with 1
+#[derive(Debug)]
+pub enum ShellError {
+    Usage(String),
+    PermissionDenied(String),
+    NotFound(String),
+    Interrupted(String),
+}
+
+impl ShellError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ShellError::Usage(_) => 2,
+            ShellError::PermissionDenied(_) => 126,
+            ShellError::NotFound(_) => 127,
+            ShellError::Interrupted(_) => 130,
+        }
+    }
+
+    // Ctrl-C stopped a builtin after it `completed` some work, the `skipped`
+    // operands were left untouched or only partly done
+    pub fn interrupted(completed: String, skipped: &[String]) -> Self {
+        let mut msg = format!("interrupted after {}", completed);
+        if !skipped.is_empty() {
+            let skipped: Vec<String> = skipped
+                .iter()
+                .map(|s| format!("'{}'", s))
+                .collect();
+            msg.push_str(&format!(", skipped {}", skipped.join(", ")));
+        }
+        ShellError::Interrupted(msg)
+    }
+}
+
+impl std::fmt::Display for ShellError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            | ShellError::Usage(msg)
+            | ShellError::PermissionDenied(msg)
+            | ShellError::NotFound(msg)
+            | ShellError::Interrupted(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ShellError {}
+
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    error.downcast_ref::<ShellError>().map_or(1, |e| e.exit_code())
+}
@@ -1,13 +1,121 @@
-use crate::command::{ Command, Rm };
-use crate::errors::CrateResult;
+use crate::arithmetic::evaluate;
+use crate::command::{
+    arithmetic_command,
+    AndOr,
+    Command,
+    ArithmeticFor,
+    Case,
+    CaseEnd,
+    CommandList,
+    Compound,
+    Connector,
+    Env,
+    External,
+    For,
+    If,
+    Pipeline,
+    Redirect,
+    Rm,
+    SimpleCommand,
+    Stage,
+    While,
+};
+use crate::errors::{ exit_code, CrateResult, ShellError };
+use crate::jobs::{ reset_child_signals, Job, JobState, JobTable, Terminal };
+use crate::redirection::{ report_error, write_stream, Streams };
+use crate::options::Options;
+use crate::signals::Signals;
+use crate::variables::{
+    is_valid_name,
+    parse_assignment,
+    split_element,
+    split_subscript,
+    Assignment,
+    Value,
+    Variables,
+};
 use anyhow::{ anyhow, Ok };
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{ Read, Write };
+use std::os::fd::{ AsFd, AsRawFd, OwnedFd };
+use std::os::unix::process::CommandExt;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Stdio;
 use std::result::Result::Ok as ResultOk;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
 use std::{ path::Path, result };
-use crate::helpers::{collect_data, pwd, display_ls_result};
+use crate::helpers::{collect_data, pwd, display_ls_result, tokenize, RedirectKind, Token};
 use tokio::fs::{ self, create_dir_all, read_to_string, remove_dir_all, remove_file };
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+
+// What a builtin produced: its output, the errors it reported along the way
+// and its exit status
+#[derive(Debug, Default)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub errors: Vec<String>,
+    pub status: i32,
+}
+
+impl CommandOutput {
+    fn fail(&mut self, error: impl std::fmt::Display) {
+        self.errors.push(error.to_string());
+        self.status = 1;
+    }
+}
+
+impl From<CrateResult<String>> for CommandOutput {
+    fn from(result: CrateResult<String>) -> Self {
+        let mut output = Self::default();
+        match result {
+            ResultOk(stdout) => output.stdout = stdout,
+            Err(err) => {
+                output.fail(&err);
+                output.status = exit_code(&err);
+            }
+        }
+        output
+    }
+}
+
+// What `break n` and `continue n` ask of the n-th enclosing loop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopControl {
+    Break(usize),
+    Continue(usize),
+}
 
 pub struct Executor {
     pub current_dir: String,
+    pub is_exit: bool,
+    // exit status of the last pipeline, available as `$?`
+    pub last_status: i32,
+    pub jobs: JobTable,
+    // set when the shell controls a terminal, which enables job control
+    terminal: Option<Terminal>,
+    pub signals: Arc<Signals>,
+    // where output goes instead of the terminal while a command substitution runs,
+    // or a compound command with its output redirected
+    pub capture: Option<OwnedFd>,
+    // the standard input and error of a compound command, when redirected
+    input: Option<OwnedFd>,
+    errors: Option<OwnedFd>,
+    pub vars: Variables,
+    pub options: Options,
+    // status of the last command substitution of the command being prepared
+    pub last_substitution: Option<i32>,
+    // how many loops the running command is in, and the `break` or `continue`
+    // they have to carry out
+    pub loop_depth: usize,
+    pub loop_control: Option<LoopControl>,
+    // `$0` and the positional parameters `$1`, `$2`...
+    pub script_name: String,
+    pub positional: Vec<String>,
+    // process id of the last job started in the background, available as `$!`
+    pub last_background: Option<i32>,
     pub _history: Vec<String>,
 }
 
@@ -15,29 +123,988 @@ impl Executor {
     pub fn new() -> Self {
         Self {
             current_dir: pwd(),
+            is_exit: false,
+            last_status: 0,
+            jobs: JobTable::default(),
+            terminal: None,
+            signals: Arc::new(Signals::default()),
+            capture: None,
+            input: None,
+            errors: None,
+            vars: Variables::from_env(),
+            options: Options::default(),
+            last_substitution: None,
+            loop_depth: 0,
+            loop_control: None,
+            script_name: std::env::args().next().unwrap_or_else(|| "own_shell".to_string()),
+            positional: vec![],
+            last_background: None,
             _history: vec![],
         }
     }
 
-    pub async fn execute(&mut self, command: &Command) -> CrateResult<String> {
+    pub fn is_interactive(&self) -> bool {
+        self.terminal.is_some()
+    }
+
+    // Keep the shell alive on Ctrl-C, Ctrl-\ and Ctrl-Z, see `Signals`
+    pub fn listen_signals(&mut self) -> CrateResult<()> {
+        self.signals = Signals::listen()?;
+        Ok(())
+    }
+
+    // Take over the terminal so foreground jobs can be stopped with Ctrl-Z,
+    // nothing happens when stdin is not a terminal
+    pub fn enable_job_control(&mut self) {
+        self.terminal = Terminal::acquire();
+    }
+
+    // Run the and-or chains of a line read at the prompt one after the other
+    // and return the status of the last one
+    pub async fn execute_list(&mut self, list: &CommandList) -> i32 {
+        self.signals.clear();
+        self.run_list(list).await
+    }
+
+    // Like `execute_list` for the lists inside a compound command or a command
+    // substitution, an earlier Ctrl-C still counts
+    pub fn run_list<'a>(
+        &'a mut self,
+        list: &'a CommandList
+    ) -> Pin<Box<dyn Future<Output = i32> + Send + 'a>> {
+        Box::pin(async move {
+            for and_or in &list.items {
+                self.execute_and_or(and_or).await;
+                // Ctrl-C abandons the rest of the line, `break` and `continue`
+                // the rest of the loop body
+                if self.is_exit || self.signals.is_interrupted() || self.loop_control.is_some() {
+                    break;
+                }
+            }
+            self.last_status
+        })
+    }
+
+    // `&&` runs the next pipeline only after a success, `||` only after a failure
+    async fn execute_and_or(&mut self, and_or: &AndOr) -> i32 {
+        if and_or.background {
+            if !and_or.rest.is_empty() {
+                report_error(None, "only a single pipeline can run in the background");
+                self.last_status = 1;
+                return 1;
+            }
+            if and_or.first.commands.iter().any(|stage| matches!(stage, Stage::Compound(..))) {
+                report_error(None, "compound commands can not run in the background");
+                self.last_status = 1;
+                return 1;
+            }
+            return self.execute_pipeline(&and_or.first, true).await;
+        }
+        let mut status = self.execute_pipeline(&and_or.first, false).await;
+        for (connector, pipeline) in &and_or.rest {
+            if self.is_exit || self.signals.is_interrupted() || self.loop_control.is_some() {
+                break;
+            }
+            match (connector, status == 0) {
+                (Connector::And, true) | (Connector::Or, false) => {
+                    status = self.execute_pipeline(pipeline, false).await;
+                }
+                _ => (),
+            }
+        }
+        status
+    }
+
+    async fn execute_pipeline(&mut self, pipeline: &Pipeline, background: bool) -> i32 {
+        let status = match self.run_pipeline(pipeline, background).await {
+            ResultOk(status) => status,
+            Err(err) => {
+                report_error(None, &err.to_string());
+                exit_code(&err)
+            }
+        };
+        self.last_status = status;
+        status
+    }
+
+    // Run every stage of the pipeline, connecting them with real pipes. External
+    // programs stream into each other while builtin output is fed to the next
+    // stage (or a redirection target) from a separate thread. The external
+    // programs form one job, waited for unless it runs in the background. The
+    // status is the one of the last stage.
+    async fn run_pipeline(&mut self, pipeline: &Pipeline, background: bool) -> CrateResult<i32> {
+        let last = pipeline.commands.len() - 1;
+        let mut stdin: Option<OwnedFd> = None;
+        let mut pids = vec![];
+        let mut pgid = 0;
+        let mut last_is_external = false;
+        let mut feeders = vec![];
+        let mut status = 0;
+
+        for (idx, stage) in pipeline.commands.iter().enumerate() {
+            let mut streams = Streams { stdin: stdin.take(), ..Default::default() };
+            if idx == 0 {
+                if let Some(input) = &self.input {
+                    streams.stdin = Some(input.try_clone()?);
+                }
+            }
+            if idx < last {
+                let (reader, writer) = std::io::pipe()?;
+                stdin = Some(reader.into());
+                streams.stdout = Some(writer.into());
+            } else if let Some(capture) = &self.capture {
+                streams.stdout = Some(capture.try_clone()?);
+            }
+            if let Some(errors) = &self.errors {
+                streams.stderr = Some(errors.try_clone()?);
+            }
+            let simple = match stage {
+                Stage::Simple(simple) => simple,
+                Stage::Compound(compound, redirects) => {
+                    let piped = idx < last;
+                    let stage_status = self.run_compound(compound, redirects, streams, piped, &mut feeders).await;
+                    if idx == last {
+                        status = stage_status;
+                    }
+                    continue;
+                }
+            };
+
+            self.last_substitution = None;
+            let stage = self
+                .expand_and_redirect(simple, &mut streams).await
+                .and_then(|(assignments, argv)| {
+                    if argv.is_empty() {
+                        Ok((assignments, None))
+                    } else {
+                        Command::try_from(argv).map(|command| (assignments, Some(command)))
+                    }
+                })
+                .map(|(mut assignments, command)| {
+                    match command {
+                        // `env` only changes the environment of the program it starts
+                        Some(Command::Env(Env { assignments: mut env, command: Some(ext) })) => {
+                            assignments.append(&mut env);
+                            (assignments, Some(Command::External(ext)))
+                        }
+                        command => (assignments, command),
+                    }
+                });
+            let stage_status = match stage {
+                // only assignments: they were made, the status is the one of their substitutions
+                ResultOk((_, None)) => self.last_substitution.unwrap_or(0),
+                ResultOk((assignments, Some(Command::External(ext)))) =>
+                    match self.spawn_external(&ext, &assignments, &mut streams, pgid) {
+                        ResultOk(child) => {
+                            let pid = child.id() as i32;
+                            if self.terminal.is_some() && pgid == 0 {
+                                pgid = pid;
+                            }
+                            pids.push(pid);
+                            last_is_external = idx == last;
+                            0
+                        }
+                        Err(err) => {
+                            report_error(streams.stderr.as_ref(), &err.to_string());
+                            exit_code(&err)
+                        }
+                    }
+                ResultOk((assignments, Some(command))) => {
+                    let saved = self.vars.assign_temporarily(&assignments);
+                    let output = self.execute(&command, streams.stdin.take()).await;
+                    self.vars.restore(saved);
+                    match streams.stdout.take() {
+                        Some(stdout) => {
+                            let text = output.stdout;
+                            feeders.push(
+                                std::thread::spawn(move || {
+                                    // the reader may be gone already, like `head` does
+                                    let _ = write_stream(&stdout, &text);
+                                })
+                            );
+                        }
+                        None => {
+                            let mut terminal = std::io::stdout().lock();
+                            let _ = terminal.write_all(output.stdout.as_bytes());
+                            let _ = terminal.flush();
+                        }
+                    }
+                    for error in &output.errors {
+                        report_error(streams.stderr.as_ref(), error);
+                    }
+                    output.status
+                }
+                Err(err) => {
+                    report_error(streams.stderr.as_ref(), &err.to_string());
+                    exit_code(&err)
+                }
+            };
+            if idx == last {
+                status = stage_status;
+            }
+        }
+
+        if !pids.is_empty() {
+            let job = Job::new(pgid, pids, pipeline.to_string());
+            if background {
+                let pid = job.last_pid();
+                self.last_background = Some(pid);
+                eprintln!("[{}] {}", self.jobs.add(job), pid);
+                return Ok(0);
+            }
+            let code = self.wait_foreground(job, false).await;
+            if last_is_external || code == 128 + libc::SIGTSTP {
+                status = code;
+            }
+        }
+        for feeder in feeders {
+            let _ = feeder.join();
+        }
+        Ok(status)
+    }
+
+    // Give the terminal to the job and wait until it finishes or gets stopped,
+    // a stopped job goes to the job table. `resume` continues a stopped job first.
+    async fn wait_foreground(&mut self, job: Job, resume: bool) -> i32 {
+        if let Some(terminal) = &self.terminal {
+            terminal.give_to(job.pgid);
+        }
+        let mut job = job;
+        if resume {
+            job.signal(libc::SIGCONT);
+            job.state = JobState::Running;
+        }
+        self.signals.set_foreground(job.pgid);
+        let waited = tokio::task::spawn_blocking(move || {
+            job.wait();
+            job
+        }).await;
+        self.signals.set_foreground(0);
+        if let Some(terminal) = &self.terminal {
+            terminal.reclaim();
+        }
+        let job = match waited {
+            ResultOk(job) => job,
+            Err(err) => {
+                report_error(None, &err.to_string());
+                return 1;
+            }
+        };
+
+        match job.state {
+            JobState::Stopped => {
+                let command = job.command.clone();
+                let id = self.jobs.add(job);
+                eprintln!("\n[{}]+  {:<24}{}", id, "Stopped", command);
+                128 + libc::SIGTSTP
+            }
+            JobState::Done(code) => {
+                if let Some(signal) = job.killed_by {
+                    if signal == libc::SIGINT {
+                        // the terminal sent Ctrl-C to the job only, act as if we got it too
+                        self.signals.interrupt();
+                        eprintln!();
+                    } else if signal != libc::SIGPIPE {
+                        // like other shells, stay quiet for broken pipelines
+                        eprintln!("{}: terminated by signal {}", job.command, signal);
+                    }
+                }
+                code
+            }
+            JobState::Running => 0,
+        }
+    }
+
+    fn jobs(&mut self) -> CrateResult<String> {
+        Ok(self.jobs.list())
+    }
+
+    async fn fg(&mut self, spec: &Option<String>) -> CommandOutput {
+        self.jobs.update();
+        match self.jobs.take(spec.as_deref()) {
+            ResultOk(job) => {
+                println!("{}", job.command);
+                CommandOutput {
+                    status: self.wait_foreground(job, true).await,
+                    ..Default::default()
+                }
+            }
+            Err(err) => CrateResult::<String>::Err(anyhow!("fg: {}", err)).into(),
+        }
+    }
+
+    fn bg(&mut self, spec: &Option<String>) -> CrateResult<String> {
+        self.jobs.update();
+        let mut job = self.jobs.take(spec.as_deref()).map_err(|e| anyhow!("bg: {}", e))?;
+        job.signal(libc::SIGCONT);
+        job.state = JobState::Running;
+        let command = job.command.clone();
+        let id = self.jobs.add(job);
+        Ok(format!("[{}]+ {} &\n", id, command))
+    }
+
+    // `export NAME...` exports the variables, assignments like in
+    // `export NAME=value` were made already by `expand_and_redirect`
+    fn export(&mut self, names: &[String]) -> CrateResult<String> {
+        if names.is_empty() {
+            return Ok(self.vars.listing(true, "export "));
+        }
+        let mut invalid = vec![];
+        for name in names {
+            if !is_valid_name(name) {
+                invalid.push(format!("export: `{}': not a valid identifier", name));
+                continue;
+            }
+            self.vars.export(name);
+        }
+        match invalid.is_empty() {
+            true => Ok(String::new()),
+            false => Err(anyhow!(invalid.join("\n"))),
+        }
+    }
+
+    // `declare [-aAxp] [NAME...]` gives the variables their attributes: `-a`
+    // and `-A` make indexed and associative arrays, `-x` exports and `-p`
+    // shows them. Assignments were made already by `expand_and_redirect`.
+    fn declare(&mut self, args: &[String]) -> CrateResult<String> {
+        let options = args.iter().take_while(|arg| arg.len() > 1 && arg.starts_with('-'));
+        let flags: String = options.clone().map(|arg| &arg[1..]).collect();
+        let names = &args[options.count()..];
+        if let Some(flag) = flags.chars().find(|flag| !"aAxp".contains(*flag)) {
+            return Err(ShellError::Usage(format!("declare: -{}: invalid option", flag)).into());
+        }
+        if names.is_empty() {
+            let all = self.vars.names();
+            return Ok(all.iter().filter_map(|name| self.vars.declaration(name)).collect());
+        }
+        let mut res = String::new();
+        for name in names {
+            if !is_valid_name(name) {
+                return Err(anyhow!("declare: `{}': not a valid identifier", name));
+            }
+            if flags.contains('a') || flags.contains('A') {
+                self.vars.declare_array(name, flags.contains('A'))?;
+            }
+            if flags.contains('x') {
+                self.vars.export(name);
+            }
+            if flags.contains('p') {
+                match self.vars.declaration(name) {
+                    Some(declaration) => res.push_str(&declaration),
+                    None => {
+                        return Err(anyhow!("declare: {}: not found", name));
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    // `unset NAME` removes a variable, `unset NAME[subscript]` an element
+    fn unset(&mut self, names: &[String]) -> CrateResult<String> {
+        for name in names.iter().filter(|name| name.as_str() != "-v") {
+            if let Some((name, subscript)) = split_subscript(name) {
+                let subscript = self.vars.subscript(name, subscript)?;
+                self.vars.unset_element(name, &subscript);
+                continue;
+            }
+            if !is_valid_name(name) {
+                return Err(anyhow!("unset: `{}': not a valid identifier", name));
+            }
+            self.vars.unset(name);
+        }
+        Ok(String::new())
+    }
+
+    // `set` lists the variables, `set -- args...` or `set args...` replaces
+    // the positional parameters
+    fn set(&mut self, args: &[String]) -> CrateResult<String> {
+        match args.first().map(String::as_str) {
+            None => Ok(self.vars.listing(false, "")),
+            Some("--") => {
+                self.positional = args[1..].to_vec();
+                Ok(String::new())
+            }
+            Some(arg) if arg.len() > 1 && arg.starts_with(['-', '+']) => {
+                Err(ShellError::Usage(format!("set: {}: invalid option", arg)).into())
+            }
+            Some(_) => {
+                self.positional = args.to_vec();
+                Ok(String::new())
+            }
+        }
+    }
+
+    // `shift [n]` drops the first n positional parameters, 1 by default
+    fn shift(&mut self, count: &Option<String>) -> CommandOutput {
+        let count = match count {
+            Some(count) =>
+                match count.parse::<usize>() {
+                    ResultOk(count) => count,
+                    Err(_) => {
+                        let err = ShellError::Usage(format!("shift: {}: numeric argument required", count));
+                        return CrateResult::<String>::Err(err.into()).into();
+                    }
+                }
+            None => 1,
+        };
+        if count > self.positional.len() {
+            return CommandOutput { status: 1, ..Default::default() };
+        }
+        self.positional.drain(..count);
+        CommandOutput::default()
+    }
+
+    // `shopt` lists the options, `shopt -s NAME...` turns options on, `-u`
+    // turns them off and `shopt NAME...` shows them, failing if one is off
+    fn shopt(&mut self, args: &[String]) -> CommandOutput {
+        let (on, names) = match args.first().map(String::as_str) {
+            Some("-s") => (Some(true), &args[1..]),
+            Some("-u") => (Some(false), &args[1..]),
+            Some(arg) if arg.starts_with('-') => {
+                let err = ShellError::Usage(format!("shopt: {}: invalid option", arg));
+                return CrateResult::<String>::Err(err.into()).into();
+            }
+            _ => (None, args),
+        };
+        let names: Vec<&str> = match names.is_empty() {
+            true => Options::NAMES.to_vec(),
+            false => names.iter().map(String::as_str).collect(),
+        };
+        if let Some(name) = names.iter().find(|name| self.options.get(name).is_none()) {
+            return Err(anyhow!("shopt: {}: invalid shell option name", name)).into();
+        }
+        let mut res = String::new();
+        let mut all_on = true;
+        for name in names {
+            match on {
+                Some(on) => {
+                    self.options.set(name, on);
+                }
+                None => {
+                    let value = self.options.get(name).unwrap_or_default();
+                    all_on &= value;
+                    let state = if value { "on" } else { "off" };
+                    res.push_str(&format!("{:<15}\t{}\n", name, state));
+                }
+            }
+        }
+        CommandOutput {
+            stdout: res,
+            status: (!all_on && !args.is_empty()) as i32,
+            ..Default::default()
+        }
+    }
+
+    // Evaluate the already expanded expressions in turn, the status is 0 when
+    // the last one is not zero and 1 otherwise
+    fn let_(&mut self, expressions: &[String]) -> CommandOutput {
+        let mut value = 0;
+        for expression in expressions {
+            match evaluate(expression, &mut self.vars) {
+                ResultOk(result) => {
+                    value = result;
+                }
+                Err(err) => {
+                    return Err(err).into();
+                }
+            }
+        }
+        CommandOutput { status: (value == 0) as i32, ..Default::default() }
+    }
+
+    // `((expression))` is `let "expression"`
+    async fn arithmetic(&mut self, expression: &str) -> CommandOutput {
+        match self.expand_arithmetic(expression).await {
+            ResultOk(value) => CommandOutput { status: (value == 0) as i32, ..Default::default() },
+            Err(err) => Err(err).into(),
+        }
+    }
+
+    // Run a compound command of a pipeline in the shell itself, the commands
+    // inside use the streams of the stage. In front of another stage its
+    // output is collected and passed on once it is done, like the one of
+    // builtins, since that stage only starts afterwards.
+    async fn run_compound(
+        &mut self,
+        compound: &Compound,
+        redirects: &[Redirect],
+        mut streams: Streams,
+        piped: bool,
+        feeders: &mut Vec<std::thread::JoinHandle<()>>
+    ) -> i32 {
+        if let Err(err) = self.redirect(redirects, &mut streams).await {
+            report_error(streams.stderr.as_ref(), &err.to_string());
+            return exit_code(&err);
+        }
+        let stdout = match streams.stdout.take() {
+            Some(stdout) if piped => {
+                let (mut reader, writer) = match std::io::pipe() {
+                    ResultOk(pipe) => pipe,
+                    Err(err) => {
+                        report_error(streams.stderr.as_ref(), &err.to_string());
+                        return 1;
+                    }
+                };
+                feeders.push(
+                    std::thread::spawn(move || {
+                        let mut output = vec![];
+                        let _ = reader.read_to_end(&mut output);
+                        let _ = File::from(stdout).write_all(&output);
+                    })
+                );
+                Some(writer.into())
+            }
+            stdout => stdout,
+        };
+        let capture = stdout.map(|stdout| self.capture.replace(stdout));
+        let input = streams.stdin.take().map(|stdin| self.input.replace(stdin));
+        let errors = streams.stderr.take().map(|stderr| self.errors.replace(stderr));
+
+        let status = match compound {
+            Compound::If(clause) => self.execute_if(clause).await,
+            Compound::While(clause) => self.execute_while(clause).await,
+            Compound::For(clause) => self.execute_for(clause).await,
+            Compound::ArithmeticFor(clause) => self.execute_arithmetic_for(clause).await,
+            Compound::Case(clause) => self.execute_case(clause).await,
+        };
+
+        // dropping the stage streams lets the next stage see the end of its input
+        if let Some(capture) = capture {
+            self.capture = capture;
+        }
+        if let Some(input) = input {
+            self.input = input;
+        }
+        if let Some(errors) = errors {
+            self.errors = errors;
+        }
+        status
+    }
+
+    // The list of the first condition that succeeds runs, or the one of
+    // `else`. Without either the status is 0.
+    async fn execute_if(&mut self, clause: &If) -> i32 {
+        for (condition, body) in &clause.branches {
+            let status = self.run_list(condition).await;
+            if self.is_exit || self.signals.is_interrupted() || self.loop_control.is_some() {
+                return status;
+            }
+            if status == 0 {
+                return self.run_list(body).await;
+            }
+        }
+        match &clause.otherwise {
+            Some(otherwise) => self.run_list(otherwise).await,
+            None => 0,
+        }
+    }
+
+    // The list of the first item with a pattern matching the word runs, then
+    // `;&` runs the next one as well and `;;&` looks for another match. Without
+    // a match the status is 0.
+    async fn execute_case(&mut self, clause: &Case) -> i32 {
+        let word = match self.expand_string(&clause.word).await {
+            ResultOk(word) => word,
+            Err(err) => {
+                report_error(self.errors.as_ref(), &err.to_string());
+                return exit_code(&err);
+            }
+        };
+        let mut status = 0;
+        let mut fall_through = false;
+        for item in &clause.items {
+            if !fall_through {
+                let mut matched = false;
+                for pattern in &item.patterns {
+                    match self.expand_pattern(pattern).await {
+                        ResultOk(pattern) if pattern.matches(&word) => {
+                            matched = true;
+                            break;
+                        }
+                        ResultOk(_) => (),
+                        Err(err) => {
+                            report_error(self.errors.as_ref(), &err.to_string());
+                            return exit_code(&err);
+                        }
+                    }
+                }
+                if !matched {
+                    continue;
+                }
+            }
+            status = self.run_list(&item.body).await;
+            if self.is_exit || self.signals.is_interrupted() || self.loop_control.is_some() {
+                return status;
+            }
+            match item.end {
+                CaseEnd::Break => {
+                    return status;
+                }
+                CaseEnd::FallThrough => fall_through = true,
+                CaseEnd::Continue => fall_through = false,
+            }
+        }
+        status
+    }
+
+    // The status of a loop is the one of the last body run, 0 without any
+    async fn execute_while(&mut self, clause: &While) -> i32 {
+        let mut status = 0;
+        self.loop_depth += 1;
+        loop {
+            let condition = self.run_list(&clause.condition).await;
+            if !self.next_iteration().await || (condition == 0) == clause.until {
+                break;
+            }
+            status = self.run_list(&clause.body).await;
+            if !self.next_iteration().await {
+                break;
+            }
+        }
+        self.loop_depth -= 1;
+        self.loop_status(status)
+    }
+
+    async fn execute_for(&mut self, clause: &For) -> i32 {
+        let words = match &clause.words {
+            Some(words) => {
+                let mut fields = vec![];
+                for word in words {
+                    match self.expand_word(word).await {
+                        ResultOk(expanded) => fields.extend(expanded),
+                        Err(err) => {
+                            report_error(self.errors.as_ref(), &err.to_string());
+                            return exit_code(&err);
+                        }
+                    }
+                }
+                fields
+            }
+            None => self.positional.clone(),
+        };
+        let mut status = 0;
+        self.loop_depth += 1;
+        for word in words {
+            self.vars.set(&clause.name, word);
+            status = self.run_list(&clause.body).await;
+            if !self.next_iteration().await {
+                break;
+            }
+        }
+        self.loop_depth -= 1;
+        self.loop_status(status)
+    }
+
+    // An empty condition counts as true, an error in an expression ends the
+    // loop with status 1
+    async fn execute_arithmetic_for(&mut self, clause: &ArithmeticFor) -> i32 {
+        let mut status = 0;
+        if let Err(err) = self.expand_arithmetic(&clause.init).await {
+            report_error(self.errors.as_ref(), &err.to_string());
+            return 1;
+        }
+        self.loop_depth += 1;
+        loop {
+            let condition = match clause.condition.trim().is_empty() {
+                true => Ok(1),
+                false => self.expand_arithmetic(&clause.condition).await,
+            };
+            match condition {
+                ResultOk(0) => break,
+                ResultOk(_) => (),
+                Err(err) => {
+                    report_error(self.errors.as_ref(), &err.to_string());
+                    status = 1;
+                    break;
+                }
+            }
+            status = self.run_list(&clause.body).await;
+            if !self.next_iteration().await {
+                break;
+            }
+            if let Err(err) = self.expand_arithmetic(&clause.step).await {
+                report_error(self.errors.as_ref(), &err.to_string());
+                status = 1;
+                break;
+            }
+        }
+        self.loop_depth -= 1;
+        self.loop_status(status)
+    }
+
+    // Whether a loop goes on after its condition or body ran: not after
+    // `exit`, Ctrl-C or `break`, and `continue n` with n > 1 ends it too
+    // before the enclosing loop continues
+    async fn next_iteration(&mut self) -> bool {
+        // a loop of builtins never waits, the signal listener must get to run
+        tokio::task::yield_now().await;
+        match self.loop_control.take() {
+            Some(LoopControl::Break(1)) => false,
+            Some(LoopControl::Continue(1)) => true,
+            Some(LoopControl::Break(n)) => {
+                self.loop_control = Some(LoopControl::Break(n - 1));
+                false
+            }
+            Some(LoopControl::Continue(n)) => {
+                self.loop_control = Some(LoopControl::Continue(n - 1));
+                false
+            }
+            None => !self.is_exit && !self.signals.is_interrupted(),
+        }
+    }
+
+    // A loop ended by Ctrl-C has status 130 and, like a job killed by it in
+    // `wait_foreground`, moves past the `^C` on the terminal
+    fn loop_status(&self, status: i32) -> i32 {
+        if !self.signals.is_interrupted() || status == 130 {
+            return status;
+        }
+        eprintln!();
+        130
+    }
+
+    // `break [n]` and `continue [n]` for the n-th enclosing loop, 1 by default.
+    // The commands left in the loop body are skipped once this one returns.
+    fn loop_control(&mut self, count: &Option<String>, is_break: bool) -> CommandOutput {
+        let name = if is_break { "break" } else { "continue" };
+        let count = match count.as_deref().map(str::parse::<i64>) {
+            None => 1,
+            Some(ResultOk(count)) if count > 0 => count as usize,
+            Some(ResultOk(count)) => {
+                return Err(anyhow!("{}: {}: loop count out of range", name, count)).into();
+            }
+            Some(Err(_)) => {
+                let message = format!("{}: {}: numeric argument required", name, count.as_deref().unwrap_or_default());
+                return CrateResult::<String>::Err(ShellError::Usage(message).into()).into();
+            }
+        };
+        let mut output = CommandOutput::default();
+        if self.loop_depth == 0 {
+            output.errors.push(format!("{}: only meaningful in a `for', `while', or `until' loop", name));
+            return output;
+        }
+        let count = count.min(self.loop_depth);
+        self.loop_control = Some(if is_break { LoopControl::Break(count) } else { LoopControl::Continue(count) });
+        output
+    }
+
+    // Open the redirection targets in written order
+    async fn redirect(&mut self, redirects: &[Redirect], streams: &mut Streams) -> CrateResult<()> {
+        for redirect in redirects {
+            let target = match redirect.kind {
+                // here-document bodies are not words, their quotes are kept
+                RedirectKind::HereDoc => self.expand_heredoc(&redirect.target).await?,
+                RedirectKind::HereDocQuoted => redirect.target.clone(),
+                _ => {
+                    let mut fields = self.expand_word(&redirect.target).await?;
+                    if fields.len() != 1 {
+                        return Err(anyhow!("{}: ambiguous redirect", redirect.target));
+                    }
+                    fields.remove(0)
+                }
+            };
+            streams.redirect(redirect.fd, redirect.kind, &target)?;
+        }
+        Ok(())
+    }
+
+    // Expand the command words and the leading `NAME=value` assignments, then
+    // open the redirection targets, so `echo $(cat f) > f` still reads `f`.
+    // Returns the assignments and the command words.
+    async fn expand_and_redirect(
+        &mut self,
+        simple: &SimpleCommand,
+        streams: &mut Streams
+    ) -> CrateResult<(Vec<(String, String)>, Vec<String>)> {
+        let mut words = simple.words.iter().peekable();
+        let mut prefix = vec![];
+        while let Some(assignment) = words.peek().and_then(|w| parse_assignment(w)) {
+            prefix.push(assignment);
+            words.next();
+        }
+        // the declaration builtins take assignments as arguments, they are
+        // made here, after `-a` or `-A` turned the name into an array, and
+        // only the names are left for the builtin
+        let declaration = words
+            .peek()
+            .is_some_and(|w| matches!(w.as_str(), "export" | "declare" | "typeset"));
+        let mut array = None;
+        let mut argv = vec![];
+        for word in words {
+            match parse_assignment(word) {
+                Some(assignment) if declaration => {
+                    if let Some(associative) = array {
+                        self.vars.declare_array(assignment.name, associative)?;
+                    }
+                    self.assign(&assignment).await?;
+                    argv.push(assignment.name.to_string());
+                }
+                _ if declaration && word.starts_with('-') && array.is_none() => {
+                    if word.contains('A') {
+                        array = Some(true);
+                    } else if word.contains('a') {
+                        array = Some(false);
+                    }
+                    argv.push(word.clone());
+                }
+                // `(( ))` is expanded once it is evaluated
+                _ if argv.is_empty() && arithmetic_command(word).is_some() => {
+                    argv.push(word.clone());
+                }
+                _ => argv.extend(self.expand_word(word).await?),
+            }
+        }
+        // without a command the assignments stay, like those of arrays
+        let mut assignments = vec![];
+        for assignment in prefix {
+            let array = assignment.subscript.is_some() || assignment.compound().is_some();
+            if argv.is_empty() || array {
+                self.assign(&assignment).await?;
+                continue;
+            }
+            let mut value = self.expand_assignment(assignment.value).await?;
+            if assignment.append {
+                value.insert_str(0, self.vars.get(assignment.name).unwrap_or_default());
+            }
+            assignments.push((assignment.name.to_string(), value));
+        }
+        self.redirect(&simple.redirects, streams).await?;
+        Ok((assignments, argv))
+    }
+
+    // Make an assignment word take effect: `NAME=value`, `NAME+=value`,
+    // `NAME[subscript]=value` or a whole array with `NAME=(...)`
+    pub async fn assign(&mut self, assignment: &Assignment<'_>) -> CrateResult<()> {
+        let name = assignment.name;
+        if let Some(elements) = assignment.compound() {
+            return self.assign_array(name, elements, assignment.append).await;
+        }
+        let mut value = self.expand_assignment(assignment.value).await?;
+        match assignment.subscript {
+            Some(subscript) => {
+                let subscript = self.expand_string(subscript).await?;
+                let subscript = self.vars.subscript(name, &subscript)?;
+                if assignment.append {
+                    value.insert_str(0, self.vars.element(name, &subscript).unwrap_or_default());
+                }
+                self.vars.set_element(name, &subscript, value)
+            }
+            None => {
+                if assignment.append {
+                    value.insert_str(0, self.vars.get(name).unwrap_or_default());
+                }
+                self.vars.set(name, value);
+                Ok(())
+            }
+        }
+    }
+
+    // `NAME=(a b [5]=c)` or with `+=` appended to what is there. Plain words
+    // are expanded into as many elements as they give fields, associative
+    // arrays need a `[key]=value` for each element.
+    async fn assign_array(&mut self, name: &str, body: &str, append: bool) -> CrateResult<()> {
+        let mut words = vec![];
+        for token in tokenize(body)? {
+            match token {
+                Token::Word(word) => words.push(word),
+                Token::Newline => (),
+                token => {
+                    return Err(
+                        ShellError::Usage(
+                            format!("syntax error near unexpected token `{}'", token)
+                        ).into()
+                    );
+                }
+            }
+        }
+        let associative = self.vars.is_associative(name);
+        let mut indexed = BTreeMap::new();
+        let mut keyed = BTreeMap::new();
+        match self.vars.value(name) {
+            Some(Value::Scalar(value)) if append => {
+                indexed.insert(0, value.clone());
+            }
+            Some(Value::Indexed(elements)) if append => indexed = elements.clone(),
+            Some(Value::Associative(elements)) if append => keyed = elements.clone(),
+            _ => (),
+        }
+        let mut next = indexed.keys().next_back().map_or(0, |last| last + 1);
+        for word in &words {
+            match split_element(word) {
+                Some((subscript, value)) => {
+                    let subscript = self.expand_string(subscript).await?;
+                    let value = self.expand_assignment(value).await?;
+                    if associative {
+                        keyed.insert(subscript, value);
+                        continue;
+                    }
+                    let index = evaluate(&subscript, &mut self.vars)?;
+                    let ResultOk(index) = usize::try_from(index) else {
+                        return Err(anyhow!("{}[{}]: bad array subscript", name, index));
+                    };
+                    indexed.insert(index, value);
+                    next = index + 1;
+                }
+                None if associative => {
+                    return Err(
+                        anyhow!("{}: {}: must use subscript when assigning associative array", name, word)
+                    );
+                }
+                None => {
+                    for field in self.expand_word(word).await? {
+                        indexed.insert(next, field);
+                        next += 1;
+                    }
+                }
+            }
+        }
+        let value = if associative { Value::Associative(keyed) } else { Value::Indexed(indexed) };
+        self.vars.set_value(name, value);
+        Ok(())
+    }
+
+    pub async fn execute(&mut self, command: &Command, stdin: Option<OwnedFd>) -> CommandOutput {
         match command {
-            Command::Echo(v) => self.echo(v),
-            Command::Cd(v) => self.cd(v),
-            Command::Ls(ls) => self.ls(ls).await,
-            Command::Pwd => self.pwd(),
-            Command::Cat(v) => self.cat(v).await,
-            Command::Cp(v) => self.cp(v).await,
+            Command::Echo(v) => self.echo(v).into(),
+            Command::Cd(v) => self.cd(v).into(),
+            Command::Ls(ls) => self.ls(ls).await.into(),
+            Command::Pwd => self.pwd().into(),
+            Command::Cat(v) => self.cat(v, stdin).await,
+            Command::Cp(v) => self.cp(v).await.into(),
             Command::Rm(rm) => self.rm(rm).await,
             Command::Mv(v) => self.mv(v).await,
             Command::Mkdir(v) => self.mkdir(v).await,
-            Command::Exit => self.exit(),
+            Command::Exit(code) => self.exit(*code),
+            Command::Jobs => self.jobs().into(),
+            Command::Fg(spec) => self.fg(spec).await,
+            Command::Bg(spec) => self.bg(spec).into(),
+            Command::Export(names) => self.export(names).into(),
+            Command::Declare(args) => self.declare(args).into(),
+            Command::Unset(names) => self.unset(names).into(),
+            Command::Set(args) => self.set(args).into(),
+            Command::Shift(count) => self.shift(count),
+            Command::Break(count) => self.loop_control(count, true),
+            Command::Colon => CommandOutput::default(),
+            Command::Continue(count) => self.loop_control(count, false),
+            Command::Shopt(args) => self.shopt(args),
+            Command::Let(args) => self.let_(args),
+            Command::Arithmetic(expression) => self.arithmetic(expression).await,
+            Command::Env(env) => Ok(self.vars.environment(&env.assignments)).into(),
+            // started by `run_pipeline`, which waits for it with the other stages
+            Command::External(_) => unreachable!("external programs are spawned by run_pipeline"),
         }
     }
     pub fn pwd(&self) -> CrateResult<String> {
         Ok(format!("{}\n", pwd()))
     }
-    fn exit(&self) -> CrateResult<String> {
-        Ok(String::new())
+    // Without an argument the shell exits with the status of the last command
+    fn exit(&mut self, code: Option<i32>) -> CommandOutput {
+        self.is_exit = true;
+        CommandOutput {
+            status: code.unwrap_or(self.last_status),
+            ..Default::default()
+        }
     }
     fn echo(&self, input: &String) -> CrateResult<String> {
         Ok(format!("{}\n", input))
@@ -46,22 +1113,110 @@ impl Executor {
     fn cd(&mut self, input: &String) -> CrateResult<String> {
         let mut input = input.clone();
         if input.len() == 0 {
-            if let Some(home_path) = dirs::home_dir() {
-                input = home_path.to_str().unwrap().to_string();
+            // $HOME, like `~` gives it
+            let home = self.vars
+                .get("HOME")
+                .map(String::from)
+                .or_else(|| dirs::home_dir().map(|dir| dir.to_string_lossy().to_string()));
+            if let Some(home_path) = home {
+                input = home_path;
             } else {
                 return Err(anyhow!("something wrong, please fix it!..\n"));
             }
         }
         if let std::result::Result::Ok(()) = std::env::set_current_dir(&input) {
-            self.current_dir = pwd();
+            // kept up to date for `~+` and `~-`
+            let previous = std::mem::replace(&mut self.current_dir, pwd());
+            self.vars.set("OLDPWD", previous);
+            self.vars.set("PWD", self.current_dir.clone());
             Ok(String::new())
         } else {
             Err(anyhow!("no such file or directory: {}", input))
         }
     }
 
-    async fn mkdir(&mut self, input: &Vec<String>) -> CrateResult<String> {
-        let mut res = Ok(String::new());
+    // Start an external program, streams left empty are inherited from the
+    // shell. With job control the program joins the process group `pgid`, or
+    // leads a new one when it is 0.
+    fn spawn_external(
+        &self,
+        ext: &External,
+        env: &[(String, String)],
+        streams: &mut Streams,
+        pgid: i32
+    ) -> CrateResult<std::process::Child> {
+        let stdin = streams.stdin.take();
+        let stdout = streams.stdout.take();
+        let stderr = streams.stderr.take();
+        let stdio = [&stdin, &stdout, &stderr];
+        // `PATH=dir program` looks the program up in `dir`
+        let path_var = env
+            .iter()
+            .rev()
+            .find(|(name, _)| name == "PATH")
+            .map(|(_, value)| value.as_str())
+            .or(self.vars.get("PATH"));
+        let path = ext.resolve(path_var)?;
+        let result = self.command(&path, &ext.program, &ext.args, env, stdio, pgid)?.spawn();
+        let result = match result {
+            // a script without a `#!` line is run by the shell itself
+            Err(e) if e.raw_os_error() == Some(libc::ENOEXEC) => {
+                let shell = std::env::current_exe()?;
+                let args = [vec![path.to_string_lossy().to_string()], ext.args.clone()].concat();
+                self.command(&shell, &ext.program, &args, env, stdio, pgid)?.spawn()
+            }
+            result => result,
+        };
+        result.map_err(|e| {
+            let message = format!("{}: {}", ext.program, e);
+            match e.kind() {
+                std::io::ErrorKind::PermissionDenied => ShellError::PermissionDenied(message).into(),
+                std::io::ErrorKind::NotFound => ShellError::NotFound(message).into(),
+                _ => anyhow!(message),
+            }
+        })
+    }
+
+    // The process for a program, its standard streams copied from `stdio`
+    fn command(
+        &self,
+        path: &Path,
+        program: &str,
+        args: &[String],
+        env: &[(String, String)],
+        stdio: [&Option<OwnedFd>; 3],
+        pgid: i32
+    ) -> CrateResult<std::process::Command> {
+        let mut command = std::process::Command::new(path);
+        command
+            .arg0(program)
+            .args(args)
+            .env_clear()
+            .envs(self.vars.exported(env))
+            .current_dir(&self.current_dir);
+        if self.terminal.is_some() {
+            command.process_group(pgid);
+        }
+        unsafe {
+            command.pre_exec(|| {
+                reset_child_signals();
+                std::io::Result::Ok(())
+            });
+        }
+        if let Some(stdin) = stdio[0] {
+            command.stdin(Stdio::from(stdin.try_clone()?));
+        }
+        if let Some(stdout) = stdio[1] {
+            command.stdout(Stdio::from(stdout.try_clone()?));
+        }
+        if let Some(stderr) = stdio[2] {
+            command.stderr(Stdio::from(stderr.try_clone()?));
+        }
+        Ok(command)
+    }
+
+    async fn mkdir(&mut self, input: &Vec<String>) -> CommandOutput {
+        let mut res = CommandOutput::default();
         for path in input.iter() {
             let full_path: String = if path.starts_with("/") {
                 path.to_string()
@@ -71,16 +1226,18 @@ impl Executor {
             match fs::create_dir(&full_path).await {
                 result::Result::Ok(()) => (),
                 result::Result::Err(err) => {
-                    res = Err(anyhow!(format!("cannot create directory '{}': {}", path, err)));
+                    res.fail(format!("cannot create directory '{}': {}", path, err));
                 }
             }
         }
         return res;
     }
 
-    async fn rm(&mut self, input: &Rm) -> CrateResult<String> {
-        let mut res = Ok(String::new());
-        for path in input.dirs.iter() {
+    async fn rm(&mut self, input: &Rm) -> CommandOutput {
+        let mut res = CommandOutput::default();
+        let token = self.signals.token();
+        let mut removed = 0;
+        for (idx, path) in input.dirs.iter().enumerate() {
             let full_path: String = if path.starts_with("/") {
                 path.to_string()
             } else {
@@ -88,27 +1245,60 @@ impl Executor {
             };
 
             let action = if !input.is_dir {
-                fs::remove_file(&full_path).await
+                fs::remove_file(&full_path).await.map(|_| {
+                    removed += 1;
+                    true
+                })
             } else {
-                fs::remove_dir_all(&full_path).await
+                remove_tree(Path::new(&full_path), &token, &mut removed).await
             };
 
             match action {
-                result::Result::Ok(()) => (),
+                result::Result::Ok(_) => (),
                 result::Result::Err(err) =>
                     match remove_file(&path).await {
                         result::Result::Ok(()) => (),
                         result::Result::Err(_) => {
-                            res = Err(anyhow!("cannot remove '{}': {}", path, err));
+                            res.fail(format!("cannot remove '{}': {}", path, err));
                         }
                     }
             }
+            if token.is_cancelled() {
+                let err = ShellError::interrupted(
+                    format!("removing {} entries", removed),
+                    &input.dirs[idx..]
+                );
+                res.fail(&err);
+                res.status = err.exit_code();
+                break;
+            }
         }
         return res;
     }
 
-    async fn cat(&self, input: &Vec<String>) -> CrateResult<String> {
-        let mut res = String::new();
+    async fn cat(&self, input: &Vec<String>, stdin: Option<OwnedFd>) -> CommandOutput {
+        let mut res = CommandOutput::default();
+        if input.is_empty() {
+            // no files given: copy the pipe, or the terminal when run on its own
+            let signals = self.signals.clone();
+            let content = tokio::task::spawn_blocking(move || {
+                let stdin = match stdin {
+                    Some(stdin) => stdin,
+                    None => std::io::stdin().as_fd().try_clone_to_owned()?,
+                };
+                read_until_interrupted(File::from(stdin), &signals)
+            }).await;
+            match content {
+                ResultOk(ResultOk(Some(content))) => res.stdout = content,
+                ResultOk(ResultOk(None)) => {
+                    eprintln!();
+                    res.status = 130;
+                }
+                ResultOk(Err(err)) => res.fail(err),
+                Err(err) => res.fail(err),
+            }
+            return res;
+        }
         for path in input.iter() {
             let full_path: String = if path.starts_with("/") {
                 path.to_string()
@@ -116,13 +1306,13 @@ impl Executor {
                 format!("{}/{}", self.current_dir, path)
             };
             match read_to_string(full_path).await {
-                result::Result::Ok(content) => res.push_str(&content),
+                result::Result::Ok(content) => res.stdout.push_str(&content),
                 result::Result::Err(err) => {
-                    res = format!("{}Error: {}: {}\n", res, path, err);
+                    res.fail(format!("{}: {}", path, err));
                 }
             }
         }
-        return Ok(res);
+        return res;
     }
 
     async fn cp(&self, input: &Vec<String>) -> CrateResult<String> {
@@ -167,30 +1357,37 @@ impl Executor {
             }
         }
 
-        for s in sources {
+        let token = self.signals.token();
+        for (idx, s) in sources.iter().enumerate() {
+            if token.is_cancelled() {
+                return Err(
+                    ShellError::interrupted(format!("copying {} files", idx), &sources[idx..]).into()
+                );
+            }
             let new_file_name = if is_destination_file || is_destination_not_exist {
                 destination.to_string()
             } else {
-                let filename = Path::new(&s)
+                let filename = Path::new(s)
                     .file_name()
                     .ok_or_else(|| anyhow!("Invalid source path"))?;
                 Path::new(&destination).join(filename).to_string_lossy().to_string()
             };
 
-            let copy_result = tokio::fs::copy(s, new_file_name).await;
-            match copy_result {
-                result::Result::Ok(_) => (),
-                Err(error) => {
-                    return Err(anyhow!(error));
-                }
+            // a copy stopped halfway is removed again
+            if !copy_file(Path::new(s), Path::new(&new_file_name), &token).await? {
+                return Err(
+                    ShellError::interrupted(format!("copying {} files", idx), &sources[idx..]).into()
+                );
             }
         }
         Ok(String::new())
     }
 
-    async fn mv(&self, paths: &Vec<String>) -> CrateResult<String> {
+    async fn mv(&self, paths: &Vec<String>) -> CommandOutput {
+        let mut res = CommandOutput::default();
         if paths.len() < 2 {
-            return Err(anyhow!("mv requires at least two arguments: ccsource(s) and destination"));
+            res.fail("mv requires at least two arguments: ccsource(s) and destination");
+            return res;
         }
 
         // The last argument is the destination
@@ -213,9 +1410,10 @@ impl Executor {
 
         // If we have multiple sources, destination must be a directory
         if sources.len() > 1 && dest_metadata.is_some() && !is_dest_dir {
-            return Err(
-                anyhow!("cannot move multiple files: destination '{}' is not a directory", dest)
+            res.fail(
+                format!("cannot move multiple files: destination '{}' is not a directory", dest)
             );
+            return res;
         }
 
         // Move each source to the destination
@@ -231,7 +1429,7 @@ impl Executor {
             let source_metadata = match fs::metadata(&source_path).await {
                 ResultOk(meta) => meta,
                 Err(_) => {
-                    eprintln!("cannot stat '{}': No such file or directory", source);
+                    res.fail(format!("cannot stat '{}': No such file or directory", source));
                     continue; // Skip this source and continue with others
                 }
             };
@@ -239,10 +1437,12 @@ impl Executor {
             // Determine final destination path
             let final_dest = if is_dest_dir {
                 // If destination is a directory, move source into it
-                let source_name = Path::new(source)
+                let Some(source_name) = Path::new(source)
                     .file_name()
-                    .and_then(|n| n.to_str())
-                    .ok_or_else(|| anyhow!("invalid source filename"))?;
+                    .and_then(|n| n.to_str()) else {
+                    res.fail(format!("invalid source filename '{}'", source));
+                    continue;
+                };
                 format!("{}/{}", dest_path, source_name)
             } else {
                 dest_path.clone()
@@ -268,17 +1468,21 @@ impl Executor {
                                 source_metadata.is_dir()
                             ).await
                         {
-                            eprintln!("cannot move '{}' to '{}': {}", source, dest, cross_err);
+                            res.fail(format!("cannot move '{}' to '{}': {}", source, dest, cross_err));
+                            if exit_code(&cross_err) == 130 {
+                                res.status = 130;
+                                break;
+                            }
                         }
                         continue;
                     }
-                    eprintln!("cannot move '{}' to '{}': {}", source, dest, e);
+                    res.fail(format!("cannot move '{}' to '{}': {}", source, dest, e));
                     continue; // Continue with other sources even if one fails
                 }
             }
         }
 
-        Ok(String::new())
+        res
     }
 
     async fn move_cross_device(
@@ -288,6 +1492,7 @@ impl Executor {
         is_dir: bool
     ) -> CrateResult<String> {
         if is_dir {
+            // the source stays in place when the copy was interrupted
             self.copy_directory_recursive(source, dest).await?;
             remove_dir_all(source).await.map_err(|e|
                 anyhow!("failed to remove source directory '{}': {}", source, e)
@@ -306,6 +1511,9 @@ impl Executor {
     async fn copy_directory_recursive(&self, source: &str, dest: &str) -> CrateResult<()> {
         use std::collections::VecDeque;
 
+        let token = self.signals.token();
+        let mut copied = 0;
+
         // create destination directory
         create_dir_all(dest).await.map_err(|e|
             anyhow!("failed to create directory '{}': {}", dest, e)
@@ -326,6 +1534,11 @@ impl Executor {
                     .next_entry().await
                     .map_err(|e| anyhow!("failed to read directory entry: {}", e))?
             {
+                if token.is_cancelled() {
+                    return Err(
+                        ShellError::interrupted(format!("copying {} files", copied), &[]).into()
+                    );
+                }
                 let entry_path = entry.path();
                 let entry_name = entry.file_name();
                 let dest_path = Path::new(&current_dest).join(&entry_name);
@@ -354,16 +1567,20 @@ impl Executor {
                     ));
                 } else {
                     // copy file
-                    fs
-                        ::copy(&entry_path, &dest_path).await
-                        .map_err(|e|
-                            anyhow!(
-                                "failed to copy file '{}' to '{}': {}",
-                                entry_path.display(),
-                                dest_path.display(),
-                                e
-                            )
-                        )?;
+                    let complete = copy_file(&entry_path, &dest_path, &token).await.map_err(|e|
+                        anyhow!(
+                            "failed to copy file '{}' to '{}': {}",
+                            entry_path.display(),
+                            dest_path.display(),
+                            e
+                        )
+                    )?;
+                    if !complete {
+                        return Err(
+                            ShellError::interrupted(format!("copying {} files", copied), &[]).into()
+                        );
+                    }
+                    copied += 1;
                 }
             }
         }
@@ -373,9 +1590,103 @@ impl Executor {
 
     async fn ls(&self, ls: &crate::command::Ls) -> CrateResult<String> {
         // let directories = collect_data(ls.is_all, ls.is_classify, ls.is_listing, ls.dirs.clone());
-        match collect_data(ls.is_all, ls.is_classify, ls.is_listing, ls.dirs.clone()) {
+        let token = self.signals.token();
+        match collect_data(ls.is_all, ls.is_classify, ls.is_listing, ls.dirs.clone(), &token) {
             anyhow::Result::Ok(data) => Ok(display_ls_result(ls.is_all, ls.is_classify, ls.is_listing, data)),
             anyhow::Result::Err(err) => Err(err),
         }
     }
 }
+
+// Remove a directory tree entry by entry, counting them in `removed`. Stops
+// between two entries once `token` is cancelled and then returns false, with
+// the tree partly removed. A symbolic link is removed itself, never followed.
+async fn remove_tree(
+    path: &Path,
+    token: &CancellationToken,
+    removed: &mut usize
+) -> std::io::Result<bool> {
+    if !fs::symlink_metadata(path).await?.is_dir() {
+        fs::remove_file(path).await?;
+        *removed += 1;
+        return std::io::Result::Ok(true);
+    }
+    // directories are removed on their second visit, once they are empty
+    let mut stack = vec![(path.to_path_buf(), false)];
+    while let Some((dir, emptied)) = stack.pop() {
+        if token.is_cancelled() {
+            return std::io::Result::Ok(false);
+        }
+        if emptied {
+            fs::remove_dir(&dir).await?;
+            *removed += 1;
+            continue;
+        }
+        let mut entries = fs::read_dir(&dir).await?;
+        stack.push((dir, true));
+        while let Some(entry) = entries.next_entry().await? {
+            if token.is_cancelled() {
+                return std::io::Result::Ok(false);
+            }
+            if entry.file_type().await?.is_dir() {
+                stack.push((entry.path(), false));
+            } else {
+                fs::remove_file(entry.path()).await?;
+                *removed += 1;
+            }
+        }
+    }
+    std::io::Result::Ok(true)
+}
+
+// Copy a file chunk by chunk, like `fs::copy` with the permissions of the
+// source. Stops between two chunks once `token` is cancelled and then removes
+// the partial target and returns false.
+async fn copy_file(
+    source: &Path,
+    target: &Path,
+    token: &CancellationToken
+) -> std::io::Result<bool> {
+    let mut reader = fs::File::open(source).await?;
+    let permissions = reader.metadata().await?.permissions();
+    let mut writer = fs::File::create(target).await?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        if token.is_cancelled() {
+            drop(writer);
+            fs::remove_file(target).await?;
+            return std::io::Result::Ok(false);
+        }
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        writer.write_all(&buf[..len]).await?;
+    }
+    writer.flush().await?;
+    fs::set_permissions(target, permissions).await?;
+    std::io::Result::Ok(true)
+}
+
+// Read the whole file, polling so that Ctrl-C can stop a read from the
+// terminal. Returns None when interrupted.
+fn read_until_interrupted(mut file: File, signals: &Signals) -> std::io::Result<Option<String>> {
+    let mut content = vec![];
+    let mut buf = [0u8; 4096];
+    loop {
+        if signals.is_interrupted() {
+            return std::io::Result::Ok(None);
+        }
+        let mut fds = libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut fds, 1, 100) } <= 0 {
+            continue;
+        }
+        match file.read(&mut buf)? {
+            0 => break,
+            n => content.extend_from_slice(&buf[..n]),
+        }
+    }
+    String::from_utf8(content)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
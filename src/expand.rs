@@ -0,0 +1,1177 @@
+use std::future::Future;
+use std::path::Path;
+use std::io::Read;
+use std::pin::Pin;
+use crate::command::CommandList;
+use crate::arithmetic::evaluate;
+use crate::braces::expand_braces;
+use crate::executor::Executor;
+use crate::glob::glob;
+use anyhow::anyhow;
+use crate::errors::CrateResult;
+use crate::helpers::{
+    decode_ansi_c,
+    find_closing_backtick,
+    find_closing_quote,
+    find_parameter_end,
+    find_substitution_end,
+    find_subscript_end,
+    tokenize,
+    user_home,
+};
+use crate::pattern::{ escape, has_wildcards, Pattern };
+use crate::redirection::report_error;
+use crate::variables::is_valid_name;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    // split unquoted expansions into fields
+    Fields,
+    // a single string, like the default word of `${VAR:-word}`
+    String,
+    // the value of an assignment, a single string with tildes expanded at the
+    // start and after each `:`
+    Assignment,
+    // a single string for the pattern matcher, quoted characters are escaped
+    Pattern,
+}
+
+// What a `$` expansion gives: a string, or for `${arr[@]}` and `${arr[*]}`
+// the elements of the array
+#[derive(Debug)]
+enum Expansion {
+    Text(String),
+    // separate fields, even inside double quotes
+    Words(Vec<String>),
+    // joined with the first character of `$IFS` inside double quotes
+    Joined(Vec<String>),
+}
+
+impl Expansion {
+    fn into_text(self, separator: &str) -> String {
+        match self {
+            Expansion::Text(text) => text,
+            Expansion::Words(words) => words.join(" "),
+            Expansion::Joined(words) => words.join(separator),
+        }
+    }
+}
+
+// the field separators when `IFS` is not set
+const DEFAULT_IFS: &str = " \t\n";
+
+// The fields a word expands to, built while scanning it
+#[derive(Debug, Default)]
+struct Fields {
+    // each field with its pattern when it has unquoted wildcards
+    done: Vec<(String, Option<String>)>,
+    current: String,
+    // the current field for pathname expansion, quoted characters escaped
+    glob: String,
+    wildcard: bool,
+    // set once the current field exists, even empty like `""`
+    started: bool,
+    // set by a quoted `${arr[@]}` of an empty array
+    vanish: bool,
+    pattern: bool,
+    // the characters unquoted expansions are split on
+    ifs: String,
+}
+
+impl Fields {
+    fn push(&mut self, ch: char) {
+        self.current.push(ch);
+        self.glob.push(ch);
+        self.wildcard |= matches!(ch, '*' | '?' | '[' | '(');
+        self.started = true;
+    }
+
+    fn push_str(&mut self, text: &str) {
+        self.current.push_str(text);
+        self.glob.push_str(text);
+        self.wildcard |= text.contains(['*', '?', '[', '(']);
+        self.started |= !text.is_empty();
+    }
+
+    // Quoted text, which matches only itself in a pattern
+    fn push_literal(&mut self, text: &str) {
+        if self.pattern {
+            self.current.push_str(&escape(text));
+        } else {
+            self.current.push_str(text);
+        }
+        self.glob.push_str(&escape(text));
+        self.started |= !text.is_empty();
+    }
+
+    fn quoted(&mut self) {
+        self.started = true;
+    }
+
+    // The elements of `${arr[@]}`, each one starting a new field. Quoted and
+    // without any elements they leave no field behind.
+    fn push_words(&mut self, words: &[String], quoted: bool) {
+        if words.is_empty() && quoted {
+            self.vanish = true;
+        }
+        for (idx, word) in words.iter().enumerate() {
+            if idx > 0 {
+                self.started |= quoted;
+                self.end();
+            }
+            if quoted {
+                self.quoted();
+                self.push_literal(word);
+            } else {
+                self.push_split(word);
+            }
+        }
+    }
+
+    // Results of unquoted expansions are split into fields on the characters
+    // of `$IFS`. Runs of IFS whitespace separate fields and are dropped at the
+    // start and the end, any other IFS character ends a field, even an empty
+    // one, together with the whitespace around it.
+    fn push_split(&mut self, text: &str) {
+        let ifs = std::mem::take(&mut self.ifs);
+        let white = |ch: char| ifs.contains(ch) && matches!(ch, ' ' | '\t' | '\n');
+        let mut chars = text.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if !ifs.contains(ch) {
+                self.push(ch);
+                continue;
+            }
+            let mut delimiter = !white(ch);
+            while let Some(&next) = chars.peek() {
+                if white(next) {
+                    chars.next();
+                } else if ifs.contains(next) && !delimiter {
+                    delimiter = true;
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            self.started |= delimiter;
+            self.end();
+        }
+        self.ifs = ifs;
+    }
+
+    fn end(&mut self) {
+        if std::mem::take(&mut self.vanish) && self.current.is_empty() {
+            self.started = false;
+        }
+        if self.started {
+            let glob = std::mem::take(&mut self.glob);
+            let pattern = if self.wildcard { Some(glob) } else { None };
+            self.done.push((std::mem::take(&mut self.current), pattern));
+            self.started = false;
+            self.wildcard = false;
+        }
+    }
+
+    fn finish(mut self) -> Vec<(String, Option<String>)> {
+        self.end();
+        self.done
+    }
+}
+
+impl Executor {
+    // Turn a raw word into its final fields: braces are expanded first, then
+    // quotes and escapes are removed, parameters and command substitutions
+    // outside single quotes are replaced by their value and unquoted wildcards
+    // by the matching paths. Only unquoted expansions can produce several
+    // fields (or none at all).
+    pub async fn expand_word(&mut self, word: &str) -> CrateResult<Vec<String>> {
+        let mut fields = vec![];
+        for word in expand_braces(word) {
+            fields.extend(self.expand(&word, Mode::Fields).await?);
+        }
+        Ok(fields)
+    }
+
+    // Expand a word that is never split
+    pub async fn expand_string(&mut self, word: &str) -> CrateResult<String> {
+        Ok(self.expand(word, Mode::String).await?.concat())
+    }
+
+    // Expand the value of a `NAME=value` assignment, which is never split
+    pub async fn expand_assignment(&mut self, value: &str) -> CrateResult<String> {
+        Ok(self.expand(value, Mode::Assignment).await?.concat())
+    }
+
+    // Expand a word used as a pattern, only its unquoted wildcards stay
+    // special, and compile it with the extglob groups when they are enabled
+    pub async fn expand_pattern(&mut self, word: &str) -> CrateResult<Pattern> {
+        let pattern = self.expand(word, Mode::Pattern).await?.concat();
+        Ok(match self.options.extglob {
+            true => Pattern::extended(&pattern),
+            false => Pattern::new(&pattern),
+        })
+    }
+
+    async fn expand(&mut self, word: &str, mode: Mode) -> CrateResult<Vec<String>> {
+        let chars: Vec<char> = word.chars().collect();
+        let mut fields = Fields {
+            pattern: mode == Mode::Pattern,
+            ifs: self.vars.get("IFS").unwrap_or(DEFAULT_IFS).to_string(),
+            ..Default::default()
+        };
+        let mut in_double = false;
+        let mut i = 0;
+
+        while i < chars.len() {
+            let ch = chars[i];
+
+            match ch {
+                '$' if !in_double && chars.get(i + 1) == Some(&'\'') => {
+                    match find_closing_quote(&chars, i + 1, '\'') {
+                        Some(closing_pos) => {
+                            fields.quoted();
+                            fields.push_literal(&decode_ansi_c(&chars[i + 2..closing_pos]));
+                            i = closing_pos + 1;
+                        }
+                        None => {
+                            fields.push(ch);
+                            i += 1;
+                        }
+                    }
+                }
+                // a `$"..."` string is translated in bash, here it is just quoted
+                '$' if !in_double && chars.get(i + 1) == Some(&'"') => {
+                    i += 1;
+                }
+                '\'' if !in_double => {
+                    match find_closing_quote(&chars, i, ch) {
+                        Some(closing_pos) => {
+                            fields.quoted();
+                            fields.push_literal(&chars[i + 1..closing_pos].iter().collect::<String>());
+                            i = closing_pos + 1;
+                        }
+                        None => {
+                            fields.push(ch);
+                            i += 1;
+                        }
+                    }
+                }
+                '"' => {
+                    in_double = !in_double;
+                    fields.quoted();
+                    i += 1;
+                }
+                '\\' => {
+                    match chars.get(i + 1) {
+                        Some('\n') => {
+                            i += 2;
+                        }
+                        // inside double quotes only these can be escaped
+                        Some(&next_char) if matches!(next_char, '"' | '\\' | '$' | '`') => {
+                            fields.push_literal(&next_char.to_string());
+                            i += 2;
+                        }
+                        // outside quotes any character can be escaped
+                        Some(&next_char) if !in_double => {
+                            fields.push_literal(&next_char.to_string());
+                            i += 2;
+                        }
+                        _ => {
+                            fields.push_literal(&ch.to_string());
+                            i += 1;
+                        }
+                    }
+                }
+                '$' | '`' => {
+                    match self.expand_dollar(&chars, i, in_double).await? {
+                        Some((value, next)) => {
+                            match value {
+                                Expansion::Words(words) | Expansion::Joined(words) if
+                                    mode == Mode::Fields &&
+                                    !(in_double && matches!(value, Expansion::Joined(_)))
+                                => fields.push_words(&words, in_double),
+                                value if in_double => {
+                                    fields.push_literal(&value.into_text(&self.separator()));
+                                }
+                                value if mode != Mode::Fields => {
+                                    fields.push_str(&value.into_text(&self.separator()));
+                                }
+                                value => fields.push_split(&value.into_text(&self.separator())),
+                            }
+                            i = next;
+                        }
+                        None => {
+                            fields.push_literal(&ch.to_string());
+                            i += 1;
+                        }
+                    }
+                }
+                _ if in_double => {
+                    fields.push_literal(&ch.to_string());
+                    i += 1;
+                }
+                '~' if
+                    (i == 0 && mode != Mode::String) ||
+                    (mode == Mode::Assignment && chars[i - 1] == ':')
+                => {
+                    match self.expand_tilde(&chars, i, mode == Mode::Assignment) {
+                        Some((dir, next)) => {
+                            fields.push_literal(&dir);
+                            i = next;
+                        }
+                        None => {
+                            fields.push(ch);
+                            i += 1;
+                        }
+                    }
+                }
+                _ => {
+                    fields.push(ch);
+                    i += 1;
+                }
+            }
+        }
+
+        if mode != Mode::Fields {
+            return Ok(fields.finish().into_iter().map(|(field, _)| field).collect());
+        }
+        let mut res = vec![];
+        for (field, pattern) in fields.finish() {
+            match pattern {
+                Some(pattern) => res.extend(self.expand_pathname(field, &pattern)?),
+                None => res.push(field),
+            }
+        }
+        Ok(res)
+    }
+
+    // The directory a tilde prefix like `~`, `~user`, `~+` or `~-` at `start`
+    // stands for and the index after it. The prefix ends at the first slash,
+    // or colon in an assignment, and is kept as is when part of it is quoted.
+    fn expand_tilde(
+        &self,
+        chars: &[char],
+        start: usize,
+        assignment: bool
+    ) -> Option<(String, usize)> {
+        let end = chars[start..]
+            .iter()
+            .position(|&c| c == '/' || (assignment && c == ':'))
+            .map_or(chars.len(), |len| start + len);
+        let name: String = chars[start + 1..end].iter().collect();
+        if name.contains(['\'', '"', '\\', '$', '`']) {
+            return None;
+        }
+        let dir = match name.as_str() {
+            "" =>
+                self.vars
+                    .get("HOME")
+                    .map(String::from)
+                    .or_else(|| dirs::home_dir().map(|dir| dir.to_string_lossy().to_string())),
+            "+" => self.vars.get("PWD").map(String::from),
+            "-" => self.vars.get("OLDPWD").map(String::from),
+            name => user_home(name),
+        };
+        Some((dir?, end))
+    }
+
+    // The paths a field with unquoted wildcards stands for. Without any the
+    // field stays as it is, disappears with `nullglob` or is an error with
+    // `failglob`.
+    fn expand_pathname(&self, field: String, pattern: &str) -> CrateResult<Vec<String>> {
+        if !has_wildcards(pattern, self.options.extglob) {
+            return Ok(vec![field]);
+        }
+        let paths = glob(pattern, Path::new(&self.current_dir), &self.options);
+        if !paths.is_empty() {
+            Ok(paths)
+        } else if self.options.failglob {
+            Err(anyhow!("no match: {}", field))
+        } else if self.options.nullglob {
+            Ok(vec![])
+        } else {
+            Ok(vec![field])
+        }
+    }
+
+    // Expand the body of a here-document with an unquoted delimiter: quotes
+    // are kept, a backslash only escapes `$`, `` ` ``, `\` and the newline
+    pub async fn expand_heredoc(&mut self, body: &str) -> CrateResult<String> {
+        let chars: Vec<char> = body.chars().collect();
+        let mut result = String::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let ch = chars[i];
+
+            match (ch, chars.get(i + 1)) {
+                ('\\', Some('\n')) => {
+                    i += 2;
+                }
+                ('\\', Some(&next_char)) if matches!(next_char, '$' | '`' | '\\') => {
+                    result.push(next_char);
+                    i += 2;
+                }
+                ('$' | '`', _) => {
+                    match self.expand_dollar(&chars, i, true).await? {
+                        Some((value, next)) => {
+                            result.push_str(&value.into_text(&self.separator()));
+                            i = next;
+                        }
+                        None => {
+                            result.push(ch);
+                            i += 1;
+                        }
+                    }
+                }
+                _ => {
+                    result.push(ch);
+                    i += 1;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Expand the parameter or command substitution starting with the `$` or
+    // backquote at `i`. Returns its value and the index right after it, or
+    // None when the character is literal.
+    async fn expand_dollar(
+        &mut self,
+        chars: &[char],
+        i: usize,
+        in_double: bool
+    ) -> CrateResult<Option<(Expansion, usize)>> {
+        if chars[i] == '`' {
+            let Some(end) = find_closing_backtick(chars, i) else {
+                return Ok(None);
+            };
+            let command = unescape_backquoted(&chars[i + 1..end], in_double);
+            return Ok(Some((Expansion::Text(self.substitute(&command).await), end + 1)));
+        }
+        let expansion = match chars.get(i + 1) {
+            Some('(') if chars.get(i + 2) == Some(&'(') && is_arithmetic(chars, i) => {
+                let end = find_substitution_end(chars, i).unwrap_or_default();
+                let expression: String = chars[i + 3..end - 1].iter().collect();
+                let value = self.expand_arithmetic(&expression).await?;
+                Some((Expansion::Text(value.to_string()), end + 1))
+            }
+            Some('(') =>
+                match find_substitution_end(chars, i) {
+                    Some(end) => {
+                        let command: String = chars[i + 2..end].iter().collect();
+                        Some((Expansion::Text(self.substitute(&command).await), end + 1))
+                    }
+                    None => None,
+                }
+            Some('{') =>
+                match find_parameter_end(chars, i) {
+                    Some(end) => {
+                        let content: String = chars[i + 2..end].iter().collect();
+                        Some((self.expand_parameter(&content).await?, end + 1))
+                    }
+                    None => None,
+                }
+            Some(_) => {
+                let len = parameter_name_len(&chars[i + 1..]);
+                if len == 0 {
+                    None
+                } else {
+                    let name: String = chars[i + 1..i + 1 + len].iter().collect();
+                    let expansion = match name.as_str() {
+                        "@" | "*" => wrap(self.positional.clone(), Some(name == "*")),
+                        _ => Expansion::Text(self.parameter(&name).unwrap_or_default()),
+                    };
+                    Some((expansion, i + 1 + len))
+                }
+            }
+            None => None,
+        };
+        Ok(expansion)
+    }
+
+    // Evaluate an arithmetic expression after expanding the parameters and
+    // command substitutions in it
+    pub fn expand_arithmetic<'a>(
+        &'a mut self,
+        expression: &'a str
+    ) -> Pin<Box<dyn Future<Output = CrateResult<i64>> + Send + 'a>> {
+        Box::pin(async move {
+            let expression = self.expand_string(expression).await?;
+            evaluate(&expression, &mut self.vars)
+        })
+    }
+
+    // The value of a parameter, None when it is not set. `$@` and `$*` give
+    // the positional parameters joined here, see `lookup` for their words.
+    pub fn parameter(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "#" => Some(self.positional.len().to_string()),
+            "$" => Some(std::process::id().to_string()),
+            "!" => self.last_background.map(|pid| pid.to_string()),
+            "-" => Some(self.flags()),
+            "0" => Some(self.script_name.clone()),
+            "@" => Some(self.positional.join(" ")),
+            "*" => Some(self.positional.join(&self.separator())),
+            _ if name.starts_with(|c: char| c.is_ascii_digit()) => {
+                // `${00}` is `$0` too
+                match name.parse::<usize>().ok()? {
+                    0 => Some(self.script_name.clone()),
+                    index => self.positional.get(index - 1).cloned(),
+                }
+            }
+            _ => self.vars.get(name).map(String::from),
+        }
+    }
+
+    // What `$*` and `${arr[*]}` are joined with: the first character of
+    // `$IFS`, a space when it is not set and nothing when it is empty
+    fn separator(&self) -> String {
+        let ifs = self.vars.get("IFS").unwrap_or(DEFAULT_IFS);
+        ifs.chars().next().map(String::from).unwrap_or_default()
+    }
+
+    // The letters of `$-`: `i` for an interactive shell, `m` for job control
+    // and `B` as brace expansion is always on
+    fn flags(&self) -> String {
+        match self.is_interactive() {
+            true => "imB".to_string(),
+            false => "B".to_string(),
+        }
+    }
+
+    // Expand what is between `${` and `}`: a parameter or array element,
+    // possibly preceded by `#` for its length or `!` for the array subscripts
+    // or an indirection, and followed by one of the POSIX operators, bash's
+    // `/pattern/replacement` or an `:offset:length` substring. With `[@]` or
+    // `[*]` the operators apply to every element.
+    fn expand_parameter<'a>(
+        &'a mut self,
+        content: &'a str
+    ) -> Pin<Box<dyn Future<Output = CrateResult<Expansion>> + Send + 'a>> {
+        Box::pin(async move {
+            let bad_substitution = || anyhow!("${{{}}}: bad substitution", content);
+            if let Some(rest) = content.strip_prefix('#') {
+                if let Some((name, subscript)) = split_reference(rest) {
+                    let (values, list) = self.lookup(name, subscript).await?;
+                    let len = match list {
+                        Some(_) => values.len(),
+                        None => values.concat().chars().count(),
+                    };
+                    return Ok(Expansion::Text(len.to_string()));
+                }
+            }
+            if let Some(rest) = content.strip_prefix('!') {
+                match split_reference(rest) {
+                    Some((name, Some(subscript @ ("@" | "*")))) => {
+                        let keys = self.vars.keys(name);
+                        return Ok(wrap(keys, Some(subscript == "*")));
+                    }
+                    // `${!name}` expands the parameter named by the value of `name`
+                    Some((name, None)) => {
+                        let reference = self.parameter(name).unwrap_or_default();
+                        if split_reference(&reference).is_none() {
+                            return Err(anyhow!("{}: invalid indirect expansion", reference));
+                        }
+                        return self.expand_parameter(&reference).await;
+                    }
+                    _ => (),
+                }
+            }
+
+            let chars: Vec<char> = content.chars().collect();
+            let mut len = braced_name_len(&chars);
+            if len == 0 {
+                return Err(bad_substitution());
+            }
+            let name: String = chars[..len].iter().collect();
+            let mut subscript = None;
+            if chars.get(len) == Some(&'[') && is_valid_name(&name) {
+                let end = find_subscript_end(&chars, len).ok_or_else(bad_substitution)?;
+                subscript = Some(chars[len + 1..end].iter().collect::<String>());
+                len = end + 1;
+            }
+            let op: String = chars[len..].iter().collect();
+            let (values, list) = self.lookup(&name, subscript.as_deref()).await?;
+
+            // with a colon an empty value counts as missing too
+            let (colon, rest) = match op.strip_prefix(':') {
+                Some(rest) if rest.starts_with(['-', '=', '?', '+']) => (true, rest),
+                _ => (false, op.as_str()),
+            };
+            let missing = values.is_empty() || (colon && values.iter().all(String::is_empty));
+            let mut rest_chars = rest.chars();
+            let Some(operator) = rest_chars.next() else {
+                return Ok(wrap(values, list));
+            };
+            let word = rest_chars.as_str();
+
+            match operator {
+                '-' if missing => Ok(Expansion::Text(self.expand_string(word).await?)),
+                '=' if missing => {
+                    if !is_valid_name(&name) || list.is_some() {
+                        return Err(anyhow!("${}: cannot assign in this way", content));
+                    }
+                    let word = self.expand_string(word).await?;
+                    match subscript {
+                        Some(subscript) => {
+                            let subscript = self.expand_string(&subscript).await?;
+                            let subscript = self.vars.subscript(&name, &subscript)?;
+                            self.vars.set_element(&name, &subscript, word.clone())?;
+                        }
+                        None => self.vars.set(&name, word.clone()),
+                    }
+                    Ok(Expansion::Text(word))
+                }
+                '?' if missing => {
+                    let message = self.expand_string(word).await?;
+                    match message.is_empty() {
+                        true => Err(anyhow!("{}: parameter null or not set", name)),
+                        false => Err(anyhow!("{}: {}", name, message)),
+                    }
+                }
+                '+' if missing => Ok(Expansion::Text(String::new())),
+                '+' => Ok(Expansion::Text(self.expand_string(word).await?)),
+                '-' | '=' | '?' => Ok(wrap(values, list)),
+                '#' | '%' => {
+                    let longest = word.starts_with(operator);
+                    let word = if longest { &word[1..] } else { word };
+                    let pattern = self.expand_pattern(word).await?;
+                    let values = values
+                        .iter()
+                        .map(|value| {
+                            match operator {
+                                '#' => remove_prefix(value, &pattern, longest),
+                                _ => remove_suffix(value, &pattern, longest),
+                            }
+                        })
+                        .collect();
+                    Ok(wrap(values, list))
+                }
+                '/' => {
+                    let (anchor, word) = match word.chars().next() {
+                        Some(c @ ('/' | '#' | '%')) => (Some(c), &word[1..]),
+                        _ => (None, word),
+                    };
+                    let (pattern, replacement) = match split_unquoted(word, '/') {
+                        Some((pattern, replacement)) => (pattern, replacement),
+                        None => (word, ""),
+                    };
+                    let pattern = self.expand_pattern(pattern).await?;
+                    let replacement = self.expand_string(replacement).await?;
+                    let values = values
+                        .iter()
+                        .map(|value| replace(value, &pattern, &replacement, anchor))
+                        .collect();
+                    Ok(wrap(values, list))
+                }
+                ':' => {
+                    let (offset, length) = match split_unquoted(word, ':') {
+                        Some((offset, length)) => (offset, Some(length)),
+                        None => (word, None),
+                    };
+                    let offset = self.expand_arithmetic(offset).await?;
+                    let length = match length {
+                        Some(length) => Some(self.expand_arithmetic(length).await?),
+                        None => None,
+                    };
+                    // the elements of an array are sliced, not their text, and
+                    // the positional parameters start with `$0`
+                    let values = match subscript {
+                        None if list.is_some() => [vec![self.script_name.clone()], values].concat(),
+                        _ => values,
+                    };
+                    match list {
+                        Some(_) => Ok(wrap(slice(&values, offset, length)?, list)),
+                        None => {
+                            let chars: Vec<char> = values.concat().chars().collect();
+                            let chars = slice(&chars, offset, length)?;
+                            Ok(Expansion::Text(chars.into_iter().collect()))
+                        }
+                    }
+                }
+                _ => Err(bad_substitution()),
+            }
+        })
+    }
+
+    // The values a parameter reference stands for: none when unset, one for a
+    // scalar or an element, all the elements for `[@]` and `[*]`. The second
+    // part tells for those two whether it was `[*]`.
+    async fn lookup(
+        &mut self,
+        name: &str,
+        subscript: Option<&str>
+    ) -> CrateResult<(Vec<String>, Option<bool>)> {
+        match subscript {
+            None if matches!(name, "@" | "*") => Ok((self.positional.clone(), Some(name == "*"))),
+            None => Ok((self.parameter(name).into_iter().collect(), None)),
+            Some(all @ ("@" | "*")) => Ok((self.vars.values(name), Some(all == "*"))),
+            Some(subscript) => {
+                let subscript = self.expand_string(subscript).await?;
+                let subscript = self.vars.subscript(name, &subscript)?;
+                let value = self.vars.element(name, &subscript).map(String::from);
+                Ok((value.into_iter().collect(), None))
+            }
+        }
+    }
+
+    // Run the command of a `$(...)` or backquote substitution and return what
+    // it printed without the trailing newlines. Like in a subshell, `cd`,
+    // `exit` and variable assignments only last until the command is done.
+    fn substitute<'a>(
+        &'a mut self,
+        command: &'a str
+    ) -> Pin<Box<dyn Future<Output = String> + Send + 'a>> {
+        Box::pin(async move {
+            let list = match tokenize(command).and_then(CommandList::try_from) {
+                Ok(list) => list,
+                Err(err) => {
+                    report_error(None, &err.to_string());
+                    self.last_status = 2;
+                    return String::new();
+                }
+            };
+            let (mut reader, writer) = match std::io::pipe() {
+                Ok(pipe) => pipe,
+                Err(err) => {
+                    report_error(None, &err.to_string());
+                    self.last_status = 1;
+                    return String::new();
+                }
+            };
+            // read while the command runs, it would block on a full pipe otherwise
+            let collector = std::thread::spawn(move || {
+                let mut output = vec![];
+                let _ = reader.read_to_end(&mut output);
+                output
+            });
+
+            let current_dir = self.current_dir.clone();
+            let is_exit = self.is_exit;
+            let vars = self.vars.clone();
+            let options = self.options.clone();
+            let positional = self.positional.clone();
+            // the loops around do not reach into the substitution
+            let loop_depth = std::mem::take(&mut self.loop_depth);
+            let capture = self.capture.replace(writer.into());
+            self.run_list(&list).await;
+            self.loop_depth = loop_depth;
+            self.loop_control = None;
+            // dropping our end of the pipe lets the collector see the end of the output
+            self.capture = capture;
+            self.is_exit = is_exit;
+            self.vars = vars;
+            self.options = options;
+            self.positional = positional;
+            self.last_substitution = Some(self.last_status);
+            if self.current_dir != current_dir {
+                let _ = std::env::set_current_dir(&current_dir);
+                self.current_dir = current_dir;
+            }
+
+            let output = collector.join().unwrap_or_default();
+            String::from_utf8_lossy(&output).trim_end_matches('\n').to_string()
+        })
+    }
+}
+
+// Whether the `$((` at `start` is an arithmetic expansion rather than a command
+// substitution starting with a subshell, that is whether it ends with `))`
+fn is_arithmetic(chars: &[char], start: usize) -> bool {
+    match find_substitution_end(chars, start) {
+        Some(end) => end > start + 3 && chars[end - 1] == ')' && {
+            // the inner parentheses must close right before the outer one
+            let mut depth = 0;
+            chars[start + 2..end - 1].iter().all(|&c| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => (),
+                }
+                depth > 0
+            })
+        },
+        None => false,
+    }
+}
+
+// Inside backquotes a backslash only escapes `$`, `` ` `` and `\`, and `"`
+// too when the backquotes are within double quotes
+fn unescape_backquoted(chars: &[char], in_double: bool) -> String {
+    let mut command = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match (chars[i], chars.get(i + 1)) {
+            ('\\', Some(&next)) if matches!(next, '$' | '`' | '\\') || (in_double && next == '"') => {
+                command.push(next);
+                i += 2;
+            }
+            (ch, _) => {
+                command.push(ch);
+                i += 1;
+            }
+        }
+    }
+    command
+}
+
+// Length of the parameter name at the start of `chars`: a variable name or
+// one of the special parameters
+fn parameter_name_len(chars: &[char]) -> usize {
+    match chars.first() {
+        Some(c) if c.is_ascii_alphabetic() || *c == '_' =>
+            chars
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                .count(),
+        Some('?' | '#' | '$' | '!' | '-' | '@' | '*') => 1,
+        Some(c) if c.is_ascii_digit() => 1,
+        _ => 0,
+    }
+}
+
+// Like `parameter_name_len`, but between braces positional parameters may have
+// several digits, as in `${10}`
+fn braced_name_len(chars: &[char]) -> usize {
+    match chars.first() {
+        Some(c) if c.is_ascii_digit() => chars.iter().take_while(|c| c.is_ascii_digit()).count(),
+        _ => parameter_name_len(chars),
+    }
+}
+
+// Split a reference like `name` or `arr[subscript]` into its parts, None when
+// the text is something else
+fn split_reference(text: &str) -> Option<(&str, Option<&str>)> {
+    let chars: Vec<char> = text.chars().collect();
+    let len = braced_name_len(&chars);
+    if len == 0 {
+        return None;
+    }
+    let name = &text[..len];
+    if len == chars.len() {
+        return Some((name, None));
+    }
+    let end = find_subscript_end(&chars, len)?;
+    match (is_valid_name(name), end + 1 == chars.len()) {
+        (true, true) => Some((name, Some(&text[len + 1..text.len() - 1]))),
+        _ => None,
+    }
+}
+
+// The values of a reference as an expansion, several words for `[@]` and `[*]`
+fn wrap(values: Vec<String>, list: Option<bool>) -> Expansion {
+    match list {
+        None => Expansion::Text(values.concat()),
+        Some(false) => Expansion::Words(values),
+        Some(true) => Expansion::Joined(values),
+    }
+}
+
+// Split at the first `separator` that is not quoted or escaped
+fn split_unquoted(word: &str, separator: char) -> Option<(&str, &str)> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut i = 0;
+    let mut in_double = false;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                i += 1;
+            }
+            '"' => {
+                in_double = !in_double;
+            }
+            '\'' if !in_double => {
+                i = find_closing_quote(&chars, i, '\'')?;
+            }
+            ch if ch == separator && !in_double => {
+                let at = chars[..i].iter().map(|c| c.len_utf8()).sum();
+                return Some((&word[..at], &word[at + ch.len_utf8()..]));
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    None
+}
+
+// `${VAR#pattern}` and `${VAR##pattern}`
+fn remove_prefix(value: &str, pattern: &Pattern, longest: bool) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let mut ends: Vec<usize> = (0..=chars.len()).collect();
+    if longest {
+        ends.reverse();
+    }
+    for end in ends {
+        if pattern.matches(&chars[..end].iter().collect::<String>()) {
+            return chars[end..].iter().collect();
+        }
+    }
+    value.to_string()
+}
+
+// `${VAR%pattern}` and `${VAR%%pattern}`
+fn remove_suffix(value: &str, pattern: &Pattern, longest: bool) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let mut starts: Vec<usize> = (0..=chars.len()).collect();
+    if !longest {
+        starts.reverse();
+    }
+    for start in starts {
+        if pattern.matches(&chars[start..].iter().collect::<String>()) {
+            return chars[..start].iter().collect();
+        }
+    }
+    value.to_string()
+}
+
+// `${VAR/pattern/replacement}` replaces the first longest match, `//` every
+// match, `/#` and `/%` one anchored at the start or the end
+fn replace(value: &str, pattern: &Pattern, replacement: &str, anchor: Option<char>) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let text = |from: usize, to: usize| chars[from..to].iter().collect::<String>();
+    match anchor {
+        Some('#') => {
+            for end in (0..=chars.len()).rev() {
+                if pattern.matches(&text(0, end)) {
+                    return format!("{}{}", replacement, text(end, chars.len()));
+                }
+            }
+            return value.to_string();
+        }
+        Some('%') => {
+            for start in 0..=chars.len() {
+                if pattern.matches(&text(start, chars.len())) {
+                    return format!("{}{}", text(0, start), replacement);
+                }
+            }
+            return value.to_string();
+        }
+        _ => (),
+    }
+
+    let all = anchor == Some('/');
+    let mut result = String::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let found = (start + 1..=chars.len())
+            .rev()
+            .find(|&end| pattern.matches(&text(start, end)));
+        match found {
+            Some(end) => {
+                result.push_str(replacement);
+                start = end;
+                if !all {
+                    break;
+                }
+            }
+            None => {
+                result.push(chars[start]);
+                start += 1;
+            }
+        }
+    }
+    result.push_str(&text(start, chars.len()));
+    result
+}
+
+// `${VAR:offset}` and `${VAR:offset:length}` on the characters of a value or
+// the elements of an array, negative numbers count from the end
+fn slice<T: Clone>(items: &[T], offset: i64, length: Option<i64>) -> CrateResult<Vec<T>> {
+    let len = items.len() as i64;
+    let mut start = offset;
+    if start < 0 {
+        start += len;
+    }
+    if start < 0 || start > len {
+        return Ok(vec![]);
+    }
+    let end = match length {
+        None => len,
+        Some(length) => {
+            if length < 0 {
+                let end = len.saturating_add(length);
+                if end < start {
+                    return Err(anyhow!("{}: substring expression < 0", length));
+                }
+                end
+            } else {
+                start.saturating_add(length).min(len)
+            }
+        }
+    };
+    Ok(items[start as usize..end as usize].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use crate::variables::Value;
+
+    // An executor in a fresh directory holding `a.rs` and `b.rs`
+    fn executor(name: &str) -> Executor {
+        let name = format!("own_shell_expand_{}_{}", std::process::id(), name);
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.rs"), "").unwrap();
+        fs::write(dir.join("b.rs"), "").unwrap();
+        let mut executor = Executor::new();
+        executor.current_dir = dir.to_string_lossy().to_string();
+        executor
+    }
+
+    fn fields(ifs: &str, parts: &[(&str, bool)]) -> Vec<String> {
+        let mut fields = Fields { ifs: ifs.to_string(), ..Default::default() };
+        for (text, split) in parts {
+            match split {
+                true => fields.push_split(text),
+                false => fields.push_str(text),
+            }
+        }
+        fields.finish().into_iter().map(|(field, _)| field).collect()
+    }
+
+    fn split(ifs: &str, text: &str) -> Vec<String> {
+        fields(ifs, &[(text, true)])
+    }
+
+    #[test]
+    fn slices() {
+        let items = ['a', 'b', 'c'];
+        assert_eq!(slice(&items, 1, None).unwrap(), ['b', 'c']);
+        assert_eq!(slice(&items, -2, Some(1)).unwrap(), ['b']);
+        assert_eq!(slice(&items, 1, Some(-1)).unwrap(), ['b']);
+        assert_eq!(slice(&items, 1, Some(i64::MAX)).unwrap(), ['b', 'c']);
+        assert_eq!(slice(&items, i64::MAX, Some(i64::MAX)).unwrap(), []);
+        assert_eq!(slice(&items, i64::MIN, None).unwrap(), []);
+        assert_eq!(slice(&items, -4, None).unwrap(), []);
+        assert_eq!(slice(&items, 3, Some(1)).unwrap(), []);
+        let err = slice(&items, 1, Some(i64::MIN)).unwrap_err();
+        assert_eq!(err.to_string(), "-9223372036854775808: substring expression < 0");
+    }
+
+    #[tokio::test]
+    async fn substrings() {
+        let mut executor = Executor::new();
+        executor.vars.set("x", "abc".to_string());
+        assert_eq!(executor.expand_string("${x:1:9223372036854775807}").await.unwrap(), "bc");
+        assert_eq!(executor.expand_string("${x: -9223372036854775807}").await.unwrap(), "");
+        assert_eq!(executor.expand_string("${x:1:-1}").await.unwrap(), "b");
+        assert!(executor.expand_string("${x:2:-9223372036854775807}").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pathnames() {
+        let mut executor = executor("pathnames");
+        assert_eq!(executor.expand_word("*.rs").await.unwrap(), ["a.rs", "b.rs"]);
+        assert_eq!(executor.expand_word("'*'.rs").await.unwrap(), ["*.rs"]);
+        assert_eq!(executor.expand_word("\\*.rs").await.unwrap(), ["*.rs"]);
+        assert_eq!(executor.expand_string("*.rs").await.unwrap(), "*.rs");
+        assert_eq!(executor.expand_word("*.c").await.unwrap(), ["*.c"]);
+        fs::remove_dir_all(&executor.current_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn no_matches() {
+        let mut executor = executor("no_matches");
+        executor.options.nullglob = true;
+        assert!(executor.expand_word("*.c").await.unwrap().is_empty());
+        assert_eq!(executor.expand_word("*.rs").await.unwrap(), ["a.rs", "b.rs"]);
+        // failglob wins over nullglob
+        executor.options.failglob = true;
+        let err = executor.expand_word("*.c").await.unwrap_err();
+        assert_eq!(err.to_string(), "no match: *.c");
+        fs::remove_dir_all(&executor.current_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tildes() {
+        let mut executor = Executor::new();
+        let home = executor.vars.get("HOME").map(String::from).or_else(|| {
+            dirs::home_dir().map(|dir| dir.to_string_lossy().to_string())
+        });
+        assert_eq!(executor.expand_word("~").await.unwrap(), [home.unwrap()]);
+        assert_eq!(executor.expand_word("~root/bin").await.unwrap(), ["/root/bin"]);
+        assert_eq!(executor.expand_word("'~'root").await.unwrap(), ["~root"]);
+        assert_eq!(executor.expand_word("a~root").await.unwrap(), ["a~root"]);
+        assert_eq!(executor.expand_word("~no_such_user").await.unwrap(), ["~no_such_user"]);
+        // in an assignment also after each `:`
+        let path = executor.expand_assignment("~root:~root/bin").await.unwrap();
+        assert_eq!(path, "/root:/root/bin");
+    }
+
+    #[tokio::test]
+    async fn arrays() {
+        let mut executor = Executor::new();
+        let elements = BTreeMap::from([(0, "a b".to_string()), (3, "c".to_string())]);
+        executor.vars.set_value("arr", Value::Indexed(elements));
+        assert_eq!(executor.expand_word("\"${arr[@]}\"").await.unwrap(), ["a b", "c"]);
+        assert_eq!(executor.expand_word("${arr[@]}").await.unwrap(), ["a", "b", "c"]);
+        assert_eq!(executor.expand_word("\"${arr[*]}\"").await.unwrap(), ["a b c"]);
+        assert_eq!(executor.expand_word("${!arr[@]}").await.unwrap(), ["0", "3"]);
+        assert_eq!(executor.expand_string("${#arr[@]} ${#arr[3]}").await.unwrap(), "2 1");
+        assert_eq!(executor.expand_string("$arr|${arr[1+2]}|${arr[-1]}").await.unwrap(), "a b|c|c");
+        assert!(executor.expand_word("\"${arr[@]:5}\"").await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(split(DEFAULT_IFS, "  a  b\tc \n"), ["a", "b", "c"]);
+        assert_eq!(split(DEFAULT_IFS, " \t "), Vec::<String>::new());
+        assert_eq!(split(DEFAULT_IFS, ""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn non_whitespace() {
+        assert_eq!(split(":", "a::b:"), ["a", "", "b"]);
+        assert_eq!(split(":", ":a"), ["", "a"]);
+        assert_eq!(split(":", ":"), [""]);
+    }
+
+    #[test]
+    fn mixed() {
+        // whitespace around a non-whitespace separator belongs to it
+        assert_eq!(split(" :", " a : b  c :: d "), ["a", "b", "c", "", "d"]);
+        assert_eq!(split(" :", "a : "), ["a"]);
+    }
+
+    #[test]
+    fn empty_ifs() {
+        assert_eq!(split("", "a b"), ["a b"]);
+    }
+
+    #[test]
+    fn around_text() {
+        let parts = [("pre", false), (" a b ", true), ("post", false)];
+        assert_eq!(fields(" ", &parts), ["pre", "a", "b", "post"]);
+        assert_eq!(fields(":", &[("a", false), (":", true)]), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn expansions_are_split() {
+        let mut executor = Executor::new();
+        executor.vars.unset("IFS");
+        executor.vars.set("x", " a  b ".to_string());
+        assert_eq!(executor.expand_word("$x").await.unwrap(), ["a", "b"]);
+        assert_eq!(executor.expand_word("\"$x\"").await.unwrap(), [" a  b "]);
+        assert_eq!(executor.expand_word("<$x>").await.unwrap(), ["<", "a", "b", ">"]);
+        executor.vars.set("IFS", ":".to_string());
+        executor.vars.set("x", "a::b".to_string());
+        assert_eq!(executor.expand_word("$x").await.unwrap(), ["a", "", "b"]);
+        assert_eq!(executor.expand_string("$x").await.unwrap(), "a::b");
+    }
+
+    #[tokio::test]
+    async fn ansi_c_strings() {
+        let mut executor = executor("ansi_c_strings");
+        assert_eq!(executor.expand_word("$'a b\\tc'").await.unwrap(), ["a b\tc"]);
+        assert_eq!(executor.expand_word("$'*'.rs").await.unwrap(), ["*.rs"]);
+        assert_eq!(executor.expand_word("\"$'x'\"").await.unwrap(), ["$'x'"]);
+        assert_eq!(executor.expand_word("$\"a b\"").await.unwrap(), ["a b"]);
+        fs::remove_dir_all(&executor.current_dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+use crate::options::Options;
+use crate::pattern::{ has_wildcards, unescape, Pattern };
+
+// Pathname expansion: the sorted paths matching a pattern, relative to `cwd`
+// unless the pattern is absolute. The pattern is matched one path component
+// at a time, so wildcards never match a `/`. Names starting with a dot are
+// only matched by a component that starts with a dot too, unless `dotglob`
+// is set. A trailing slash keeps only the directories. With `globstar` a
+// `**` component matches any number of directories, or as the last one
+// everything below.
+pub fn glob(pattern: &str, cwd: &Path, options: &Options) -> Vec<String> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+    let components: Vec<&str> = rest.split('/').collect();
+    let mut checked = false;
+
+    for (idx, component) in components.iter().enumerate() {
+        let last = idx + 1 == components.len();
+        if component.is_empty() {
+            // `a//b` or the trailing slash of `*/`
+            paths = paths
+                .into_iter()
+                .filter(|path| !path.is_empty() && cwd.join(path).is_dir())
+                .map(|path| if last { format!("{}/", path) } else { path })
+                .collect();
+            checked = true;
+            continue;
+        }
+        let separator = |path: &String| {
+            if path.is_empty() || path.ends_with('/') { "" } else { "/" }
+        };
+        if options.globstar && *component == "**" {
+            paths = paths
+                .iter()
+                .flat_map(|path| walk(path, cwd, options.dotglob, !last))
+                .collect();
+            checked = true;
+            continue;
+        }
+        if !has_wildcards(component, options.extglob) {
+            let name = unescape(component);
+            for path in &mut paths {
+                *path = format!("{}{}{}", path, separator(path), name);
+            }
+            checked = false;
+            continue;
+        }
+
+        let matcher = match options.extglob {
+            true => Pattern::extended(component),
+            false => Pattern::new(component),
+        };
+        let hidden = options.dotglob || component.starts_with('.') || component.starts_with("\\.");
+        let mut next = vec![];
+        for path in &paths {
+            let dir = if path.is_empty() { cwd.to_path_buf() } else { cwd.join(path) };
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if (hidden || !name.starts_with('.')) && matcher.matches(&name) {
+                    next.push(format!("{}{}{}", path, separator(path), name));
+                }
+            }
+        }
+        paths = next;
+        checked = true;
+    }
+
+    // names spelled out after the last wildcard may not exist
+    if !checked {
+        paths.retain(|path| fs::symlink_metadata(cwd.join(path)).is_ok());
+    }
+    paths.sort();
+    paths
+}
+
+// The directory `path` and all the directories below it, or with the files
+// too when `dirs_only` is not set. Hidden ones are not entered unless
+// `dotglob` is set, and symbolic links are not followed.
+fn walk(path: &str, cwd: &Path, dotglob: bool, dirs_only: bool) -> Vec<String> {
+    let base = cwd.join(path);
+    let separator = if path.is_empty() || path.ends_with('/') { "" } else { "/" };
+    WalkDir::new(&base)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || dotglob || !entry.file_name().to_string_lossy().starts_with('.')
+        })
+        .flatten()
+        .filter(|entry| !dirs_only || entry.file_type().is_dir())
+        .filter_map(|entry| {
+            let relative = entry.path().strip_prefix(&base).ok()?.to_string_lossy().to_string();
+            Some(match (relative.is_empty(), path.is_empty()) {
+                // a trailing `**` lists the directory it is in as `dir/`
+                (true, true) if !dirs_only => {
+                    return None;
+                }
+                (true, _) if !dirs_only => format!("{}{}", path, separator),
+                (true, _) => path.to_string(),
+                (false, true) => relative,
+                (false, false) => format!("{}{}{}", path, separator, relative),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A fresh directory with the given files, `/` separated names creating
+    // the directories in between
+    fn tree(name: &str, files: &[&str]) -> PathBuf {
+        let name = format!("own_shell_glob_{}_{}", std::process::id(), name);
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn sorted_matches() {
+        let dir = tree("sorted", &["b.rs", "a.rs", "c.txt", ".hidden.rs", "src/main.rs"]);
+        let options = Options::default();
+        assert_eq!(glob("*.rs", &dir, &options), ["a.rs", "b.rs"]);
+        assert_eq!(glob("*/*.rs", &dir, &options), ["src/main.rs"]);
+        assert_eq!(glob("*/", &dir, &options), ["src/"]);
+        assert_eq!(glob("src/m?in.rs", &dir, &options), ["src/main.rs"]);
+        assert!(glob("*.c", &dir, &options).is_empty());
+        // names after the last wildcard have to exist
+        assert!(glob("*/missing", &dir, &options).is_empty());
+        let absolute = format!("{}/[ab].rs", dir.display());
+        assert_eq!(glob(&absolute, &dir, &options).len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hidden_names() {
+        let dir = tree("hidden", &[".profile", "visible"]);
+        let mut options = Options::default();
+        assert_eq!(glob("*", &dir, &options), ["visible"]);
+        assert_eq!(glob(".p*", &dir, &options), [".profile"]);
+        options.dotglob = true;
+        assert_eq!(glob("*", &dir, &options), [".profile", "visible"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn globstar() {
+        let dir = tree("globstar", &["a.rs", "src/b.rs", "src/bin/c.rs", ".git/d.rs"]);
+        let mut options = Options::default();
+        // without globstar `**` is just `*`
+        assert_eq!(glob("**/*.rs", &dir, &options), ["src/b.rs"]);
+        options.globstar = true;
+        assert_eq!(
+            glob("**/*.rs", &dir, &options),
+            ["a.rs", "src/b.rs", "src/bin/c.rs"]
+        );
+        assert_eq!(glob("**/", &dir, &options), ["src/", "src/bin/"]);
+        assert_eq!(glob("src/**", &dir, &options), ["src/", "src/b.rs", "src/bin", "src/bin/c.rs"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn extended_patterns() {
+        let dir = tree("extglob", &["a.rs", "b.c", "c.h"]);
+        let mut options = Options::default();
+        assert!(glob("!(*.c)", &dir, &options).is_empty());
+        options.extglob = true;
+        assert_eq!(glob("!(*.c)", &dir, &options), ["a.rs", "c.h"]);
+        assert_eq!(glob("*.@(c|h)", &dir, &options), ["b.c", "c.h"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
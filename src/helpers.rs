@@ -1,84 +1,111 @@
 use anyhow::{ anyhow };
-use tokio::io::{ self, AsyncBufReadExt, AsyncWriteExt, BufWriter, Stdout };
+use tokio::io::{ self, AsyncWriteExt, BufWriter, Stdout };
 use std::fs;
-use std::path::{ Path };
-use crate::errors::CrateResult;
+use std::path::{ Path, PathBuf };
+use crate::errors::{ CrateResult, ShellError };
+use crate::parser::is_incomplete;
+use crate::variables::is_valid_name;
 use chrono::Datelike;
 use std::os::unix::fs::MetadataExt;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, PartialEq)]
 enum QuoteStatus {
     Balanced,
     UnclosedSingle,
     UnclosedDouble,
+    UnclosedHereDoc,
+    // with the `)`, `}` or `` ` `` that is still missing
+    UnclosedSubstitution(char),
+    // the line ends with a backslash
+    Continued,
+    // the digits before a redirection operator are too many for a descriptor
+    BadDescriptor(String),
 }
 
-pub async fn handle_quotes(input: &str, stdout: &mut BufWriter<Stdout>) -> io::Result<Vec<String>> {
+// the lines of the terminal, a script or a `-c` string
+pub type InputLines = io::Lines<Box<dyn io::AsyncBufRead + Unpin + Send>>;
+
+// Keep reading lines until every quote is closed, every here-document has
+// reached its delimiter and every compound command is complete, then return
+// the tokens of the input. An interactive shell shows a `> ` prompt for each
+// of these lines.
+pub async fn handle_quotes(
+    input: &str,
+    reader: &mut InputLines,
+    stdout: &mut BufWriter<Stdout>,
+    interactive: bool
+) -> io::Result<Vec<Token>> {
     let mut final_input = input.to_string();
 
     loop {
-        let quote_status = check_quotes(&final_input);
+        let (tokens, quote_status) = process_shell_quotes(&final_input);
 
         match quote_status {
-            QuoteStatus::Balanced => {
-                break;
-            }
-            QuoteStatus::UnclosedSingle | QuoteStatus::UnclosedDouble => {
-                stdout.write_all(b"> ").await?;
-                stdout.flush().await?;
-
-                let stdin = io::stdin();
-                let mut reader = io::BufReader::new(stdin);
-                let mut additional_input = String::new();
-                reader.read_line(&mut additional_input).await?;
-
-                // Remove the newline character
-                if additional_input.ends_with('\n') {
-                    additional_input.pop();
-                    if additional_input.ends_with('\r') {
-                        additional_input.pop();
-                    }
-                }
-
-                final_input.push('\n');
-                final_input.push_str(&additional_input);
+            QuoteStatus::Balanced if !is_incomplete(&tokens) => {
+                return Ok(tokens);
             }
-        }
-    }
-
-    Ok(process_shell_quotes(&final_input))
-}
-
-fn check_quotes(input: &str) -> QuoteStatus {
-    let mut single_quote_count = 0;
-    let mut double_quote_count = 0;
-    let chars: Vec<char> = input.chars().collect();
-
-    for i in 0..chars.len() {
-        match chars[i] {
-            '\'' => {
-                if !is_escaped(&chars, i) && double_quote_count == 0 {
-                    single_quote_count += 1;
-                }
+            QuoteStatus::BadDescriptor(fd) => {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("{}: bad file descriptor", fd))
+                );
             }
-            '"' => {
-                if !is_escaped(&chars, i) && single_quote_count == 0 {
-                    double_quote_count += 1;
+            | QuoteStatus::Balanced
+            | QuoteStatus::UnclosedSingle
+            | QuoteStatus::UnclosedDouble
+            | QuoteStatus::UnclosedHereDoc
+            | QuoteStatus::UnclosedSubstitution(_)
+            | QuoteStatus::Continued => {
+                if interactive {
+                    stdout.write_all(b"> ").await?;
+                    stdout.flush().await?;
+                }
+
+                match reader.next_line().await? {
+                    Some(additional_input) => {
+                        final_input.push('\n');
+                        final_input.push_str(additional_input.trim_end_matches('\r'));
+                    }
+                    // an unfinished command is reported by the parser
+                    None if quote_status == QuoteStatus::Balanced => {
+                        return Ok(tokens);
+                    }
+                    // a here-document may end with the input, like in bash
+                    None if quote_status == QuoteStatus::UnclosedHereDoc => {
+                        final_input.push('\n');
+                        return Ok(process_shell_quotes(&final_input).0);
+                    }
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, quote_status.eof_message()));
+                    }
                 }
             }
-            _ => {}
         }
     }
+}
 
-    if single_quote_count % 2 != 0 {
-        return QuoteStatus::UnclosedSingle;
+// Split a complete command, like the one of a command substitution, into tokens
+pub fn tokenize(input: &str) -> CrateResult<Vec<Token>> {
+    match process_shell_quotes(input) {
+        (tokens, QuoteStatus::Balanced | QuoteStatus::UnclosedHereDoc) => Ok(tokens),
+        (_, QuoteStatus::BadDescriptor(fd)) => Err(anyhow!("{}: bad file descriptor", fd)),
+        (_, status) => Err(anyhow!(status.eof_message())),
     }
+}
 
-    if double_quote_count % 2 != 0 {
-        return QuoteStatus::UnclosedDouble;
+impl QuoteStatus {
+    // What is reported when the input ends before the quote or substitution does
+    fn eof_message(&self) -> String {
+        let missing = match self {
+            QuoteStatus::UnclosedSingle => '\'',
+            QuoteStatus::UnclosedDouble => '"',
+            QuoteStatus::UnclosedSubstitution(missing) => *missing,
+            _ => {
+                return "syntax error: unexpected end of file".to_string();
+            }
+        };
+        format!("unexpected EOF while looking for matching `{}'", missing)
     }
-
-    QuoteStatus::Balanced
 }
 
 fn is_escaped(chars: &[char], position: usize) -> bool {
@@ -97,78 +124,288 @@ fn is_escaped(chars: &[char], position: usize) -> bool {
     escape_count % 2 != 0
 }
 
-fn process_shell_quotes(input: &str) -> Vec<String> {
-    if input.is_empty() {
-        return vec![];
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectKind {
+    Input,     // <
+    Output,    // >
+    Append,    // >>
+    OutputAll, // &>
+    AppendAll, // &>>
+    Duplicate, // >& and <&
+    HereDoc,   // << and <<-, the body is expanded when used
+    HereDocQuoted, // << and <<- with a quoted delimiter, the body is kept literally
+    HereString, // <<<
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Word(String),
+    Pipe,
+    // the file descriptor the redirection applies to and its operator
+    Redirect(u32, RedirectKind),
+    Amp,
+    Semi,
+    And,
+    Or,
+    Newline,
+    LeftParen,
+    RightParen,
+    // the ends of a `case` item: `;;`, `;&` and `;;&`
+    DoubleSemi,
+    SemiAmp,
+    DoubleSemiAmp,
+}
+
+impl std::fmt::Display for RedirectKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operator = match self {
+            RedirectKind::Input => "<",
+            RedirectKind::Output => ">",
+            RedirectKind::Append => ">>",
+            RedirectKind::OutputAll => "&>",
+            RedirectKind::AppendAll => "&>>",
+            RedirectKind::Duplicate => ">&",
+            RedirectKind::HereDoc | RedirectKind::HereDocQuoted => "<<",
+            RedirectKind::HereString => "<<<",
+        };
+        write!(f, "{}", operator)
     }
+}
 
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Pipe => write!(f, "|"),
+            Token::Redirect(_, kind) => write!(f, "{}", kind),
+            Token::Amp => write!(f, "&"),
+            Token::Semi => write!(f, ";"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Newline => write!(f, "newline"),
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
+            Token::DoubleSemi => write!(f, ";;"),
+            Token::SemiAmp => write!(f, ";&"),
+            Token::DoubleSemiAmp => write!(f, ";;&"),
+        }
+    }
+}
+
+// Split the input into words and operators. Words keep their quotes and
+// backslashes untouched, so quote removal can happen once the word is used.
+// Here-document bodies start on the line after their operator and replace the
+// delimiter word. The status tells whether more input is needed.
+fn process_shell_quotes(input: &str) -> (Vec<Token>, QuoteStatus) {
     let chars: Vec<char> = input.chars().collect();
     let mut result = Vec::new();
     let mut current_token = String::new();
+    // token index of every here-document operator whose body is not read yet
+    let mut pending_heredocs: Vec<(usize, bool)> = Vec::new();
     let mut i = 0;
 
     while i < chars.len() {
         let ch = chars[i];
 
         match ch {
-            '"' => {
-                // Find the closing double quote
-                if let Some(closing_pos) = find_closing_quote(&chars, i, '"') {
-                    // Add content between quotes (preserving spaces)
-                    for j in i + 1..closing_pos {
-                        current_token.push(chars[j]);
+            '#' if current_token.is_empty() => {
+                // A comment runs to the end of the line
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '"' | '\'' => {
+                // Keep the whole quoted section (quotes included) in the word
+                let end = if ch == '"' {
+                    find_closing_double_quote(&chars, i)
+                } else {
+                    find_closing_quote(&chars, i, ch)
+                };
+                let Some(end) = end else {
+                    let status = if ch == '"' {
+                        QuoteStatus::UnclosedDouble
+                    } else {
+                        QuoteStatus::UnclosedSingle
+                    };
+                    return (result, status);
+                };
+                current_token.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '$' if matches!(chars.get(i + 1), Some('(') | Some('{')) => {
+                // The substituted command or parameter stays in the word,
+                // spaces and operators included
+                let (end, closing) = if chars[i + 1] == '(' {
+                    (find_substitution_end(&chars, i), ')')
+                } else {
+                    (find_parameter_end(&chars, i), '}')
+                };
+                let Some(end) = end else {
+                    return (result, QuoteStatus::UnclosedSubstitution(closing));
+                };
+                current_token.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '(' if current_token.is_empty() && chars.get(i + 1) == Some(&'(') => {
+                // An arithmetic command `(( ))` is a single word up to its `))`
+                let Some(end) = find_closing_paren(&chars, i) else {
+                    return (result, QuoteStatus::UnclosedSubstitution(')'));
+                };
+                current_token.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '(' if is_array_assignment(&current_token) => {
+                // `arr=(a b c)` is a single word, newlines included
+                let Some(end) = find_closing_paren(&chars, i) else {
+                    return (result, QuoteStatus::UnclosedSubstitution(')'));
+                };
+                current_token.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '(' if current_token.ends_with(['?', '*', '+', '@', '!']) => {
+                // An extglob group like `@(a|b)` is part of the word, `|` included
+                let Some(end) = find_closing_paren(&chars, i) else {
+                    return (result, QuoteStatus::UnclosedSubstitution(')'));
+                };
+                current_token.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '(' | ')' => {
+                if !current_token.is_empty() {
+                    result.push(Token::Word(current_token.clone()));
+                    current_token.clear();
+                }
+                result.push(if ch == '(' { Token::LeftParen } else { Token::RightParen });
+                i += 1;
+            }
+            '`' => {
+                let Some(end) = find_closing_backtick(&chars, i) else {
+                    return (result, QuoteStatus::UnclosedSubstitution('`'));
+                };
+                current_token.extend(&chars[i..=end]);
+                i = end + 1;
+            }
+            '\n' => {
+                if !current_token.is_empty() {
+                    result.push(Token::Word(current_token.clone()));
+                    current_token.clear();
+                }
+                result.push(Token::Newline);
+                i += 1;
+                for (idx, strip_tabs) in pending_heredocs.drain(..) {
+                    if !read_heredoc_body(&chars, &mut i, &mut result, idx, strip_tabs) {
+                        return (result, QuoteStatus::UnclosedHereDoc);
                     }
-                    i = closing_pos + 1;
+                }
+            }
+            '\\' if chars.get(i + 1) == Some(&'\n') => {
+                // A backslash-newline joins the lines
+                i += 2;
+            }
+            '\\' if i + 1 == chars.len() => {
+                return (result, QuoteStatus::Continued);
+            }
+            '\\' => {
+                // Keep the escape sequence as is, it is resolved by unquote_word
+                current_token.push(ch);
+                current_token.push(chars[i + 1]);
+                i += 2;
+            }
+            ' ' | '\t' => {
+                // Blank outside quotes - end current token
+                if !current_token.is_empty() {
+                    result.push(Token::Word(current_token.clone()));
+                    current_token.clear();
+                }
+                // Skip multiple consecutive blanks
+                while i + 1 < chars.len() && matches!(chars[i + 1], ' ' | '\t') {
+                    i += 1;
+                }
+                i += 1;
+            }
+            '|' | ';' => {
+                // Unquoted operator - end current token and emit it
+                if !current_token.is_empty() {
+                    result.push(Token::Word(current_token.clone()));
+                    current_token.clear();
+                }
+                if ch == ';' {
+                    let (token, len) = match (chars.get(i + 1), chars.get(i + 2)) {
+                        (Some(';'), Some('&')) => (Token::DoubleSemiAmp, 3),
+                        (Some(';'), _) => (Token::DoubleSemi, 2),
+                        (Some('&'), _) => (Token::SemiAmp, 2),
+                        _ => (Token::Semi, 1),
+                    };
+                    result.push(token);
+                    i += len;
+                } else if chars.get(i + 1) == Some(&'|') {
+                    result.push(Token::Or);
+                    i += 2;
                 } else {
-                    // No closing quote, treat as literal
-                    current_token.push(ch);
+                    result.push(Token::Pipe);
                     i += 1;
                 }
             }
-            '\'' => {
-                // Find the closing single quote
-                if let Some(closing_pos) = find_closing_quote(&chars, i, '\'') {
-                    // Add content between quotes (preserving spaces)
-                    for j in i + 1..closing_pos {
-                        current_token.push(chars[j]);
-                    }
-                    i = closing_pos + 1;
+            '&' if chars.get(i + 1) != Some(&'>') => {
+                if !current_token.is_empty() {
+                    result.push(Token::Word(current_token.clone()));
+                    current_token.clear();
+                }
+                if chars.get(i + 1) == Some(&'&') {
+                    result.push(Token::And);
+                    i += 2;
                 } else {
-                    // No closing quote, treat as literal
-                    current_token.push(ch);
+                    result.push(Token::Amp);
                     i += 1;
                 }
             }
-            '\\' => {
-                // Handle escaped characters
-                if i + 1 < chars.len() {
-                    let next_char = chars[i + 1];
-                    match next_char {
-                        '"' | '\'' | '\\' => {
-                            current_token.push(next_char);
-                            i += 2;
+            '<' | '>' => {
+                // A word made only of digits right before the operator is the file descriptor
+                let mut fd = if ch == '<' { 0 } else { 1 };
+                if !current_token.is_empty() {
+                    match current_token.parse::<u32>() {
+                        Ok(n) if current_token.chars().all(|c| c.is_ascii_digit()) => {
+                            fd = n;
                         }
-                        _ => {
-                            current_token.push(ch);
-                            i += 1;
+                        // no descriptor is that large
+                        Err(_) if current_token.chars().all(|c| c.is_ascii_digit()) => {
+                            return (result, QuoteStatus::BadDescriptor(current_token));
                         }
+                        _ => result.push(Token::Word(current_token.clone())),
                     }
-                } else {
-                    current_token.push(ch);
-                    i += 1;
+                    current_token.clear();
                 }
+                let (kind, len) = match (ch, chars.get(i + 1), chars.get(i + 2)) {
+                    ('<', Some('<'), Some('<')) => (RedirectKind::HereString, 3),
+                    ('<', Some('<'), Some('-')) => {
+                        pending_heredocs.push((result.len(), true));
+                        (RedirectKind::HereDoc, 3)
+                    }
+                    ('<', Some('<'), _) => {
+                        pending_heredocs.push((result.len(), false));
+                        (RedirectKind::HereDoc, 2)
+                    }
+                    ('>', Some('>'), _) => (RedirectKind::Append, 2),
+                    ('>', Some('|'), _) => (RedirectKind::Output, 2),
+                    (_, Some('&'), _) => (RedirectKind::Duplicate, 2),
+                    ('>', _, _) => (RedirectKind::Output, 1),
+                    _ => (RedirectKind::Input, 1),
+                };
+                result.push(Token::Redirect(fd, kind));
+                i += len;
             }
-            ' ' => {
-                // Space outside quotes - end current token
+            '&' if chars.get(i + 1) == Some(&'>') => {
                 if !current_token.is_empty() {
-                    result.push(current_token.clone());
+                    result.push(Token::Word(current_token.clone()));
                     current_token.clear();
                 }
-                // Skip multiple consecutive spaces
-                while i + 1 < chars.len() && chars[i + 1] == ' ' {
-                    i += 1;
+                if chars.get(i + 2) == Some(&'>') {
+                    result.push(Token::Redirect(1, RedirectKind::AppendAll));
+                    i += 3;
+                } else {
+                    result.push(Token::Redirect(1, RedirectKind::OutputAll));
+                    i += 2;
                 }
-                i += 1;
             }
             _ => {
                 current_token.push(ch);
@@ -179,21 +416,363 @@ fn process_shell_quotes(input: &str) -> Vec<String> {
 
     // Add the last token if it exists
     if !current_token.is_empty() {
-        result.push(current_token);
+        result.push(Token::Word(current_token));
+    }
+
+    if pending_heredocs.iter().any(|(idx, _)| matches!(result.get(idx + 1), Some(Token::Word(_)))) {
+        return (result, QuoteStatus::UnclosedHereDoc);
+    }
+    (result, QuoteStatus::Balanced)
+}
+
+// Whether the word so far is the `NAME=` or `NAME+=` of an array assignment
+fn is_array_assignment(word: &str) -> bool {
+    let name = word.strip_suffix("+=").or(word.strip_suffix('='));
+    name.is_some_and(is_valid_name)
+}
+
+// Read the lines of a here-document starting at `i` up to its delimiter and put
+// the body in place of the delimiter word. Returns false when the input ends
+// first, in which case the body holds everything read so far.
+fn read_heredoc_body(
+    chars: &[char],
+    i: &mut usize,
+    tokens: &mut [Token],
+    idx: usize,
+    strip_tabs: bool
+) -> bool {
+    let Some(Token::Word(raw_delimiter)) = tokens.get(idx + 1).cloned() else {
+        // missing delimiter, reported by the parser
+        return true;
+    };
+    // any quoting in the delimiter disables expansion of the body
+    if raw_delimiter.contains(['\'', '"', '\\']) {
+        if let Token::Redirect(fd, _) = tokens[idx] {
+            tokens[idx] = Token::Redirect(fd, RedirectKind::HereDocQuoted);
+        }
+    }
+    let delimiter = unquote_word(&raw_delimiter);
+    let mut body = String::new();
+
+    while *i < chars.len() {
+        let end = chars[*i..]
+            .iter()
+            .position(|&c| c == '\n')
+            .map_or(chars.len(), |p| *i + p);
+        let line: String = chars[*i..end].iter().collect();
+        let line = if strip_tabs { line.trim_start_matches('\t') } else { &line };
+        *i = end + 1;
+        if line == delimiter {
+            tokens[idx + 1] = Token::Word(body);
+            return true;
+        }
+        body.push_str(line);
+        body.push('\n');
+    }
+
+    tokens[idx + 1] = Token::Word(body);
+    false
+}
+
+// Remove the quotes and escapes of a word produced by process_shell_quotes.
+// Everything between single quotes is literal, inside double quotes a
+// backslash only escapes `$`, `` ` ``, `"`, `\` and the newline, outside
+// quotes it escapes any character. `$'...'` strings decode their escapes.
+pub fn unquote_word(word: &str) -> String {
+    let chars: Vec<char> = word.chars().collect();
+    let mut result = String::new();
+    let mut in_double = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+
+        match (ch, chars.get(i + 1)) {
+            ('$', Some('\'')) if !in_double => {
+                match find_closing_quote(&chars, i + 1, '\'') {
+                    Some(closing_pos) => {
+                        result.push_str(&decode_ansi_c(&chars[i + 2..closing_pos]));
+                        i = closing_pos + 1;
+                    }
+                    None => {
+                        result.push(ch);
+                        i += 1;
+                    }
+                }
+            }
+            ('\'', _) if !in_double => {
+                match find_closing_quote(&chars, i, ch) {
+                    Some(closing_pos) => {
+                        result.extend(&chars[i + 1..closing_pos]);
+                        i = closing_pos + 1;
+                    }
+                    // No closing quote, treat as literal
+                    None => {
+                        result.push(ch);
+                        i += 1;
+                    }
+                }
+            }
+            ('"', _) => {
+                in_double = !in_double;
+                i += 1;
+            }
+            ('\\', Some('\n')) => {
+                i += 2;
+            }
+            ('\\', Some(&next_char)) if !in_double || matches!(next_char, '$' | '`' | '"' | '\\') => {
+                result.push(next_char);
+                i += 2;
+            }
+            _ => {
+                result.push(ch);
+                i += 1;
+            }
+        }
     }
 
     result
 }
 
-fn find_closing_quote(chars: &[char], start: usize, quote_char: char) -> Option<usize> {
+// Decode the escapes of a `$'...'` string: `\n`, `\t` and the other C ones,
+// `\e` for escape, octal `\nnn`, hexadecimal `\xHH`, unicode `\uHHHH` and
+// `\UHHHHHHHH`, and control characters like `\cA`
+pub fn decode_ansi_c(chars: &[char]) -> String {
+    let mut result = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] != '\\' || i + 1 == chars.len() {
+            result.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let next = chars[i + 1];
+        i += 2;
+        let simple = match next {
+            'a' => Some('\x07'),
+            'b' => Some('\x08'),
+            'e' | 'E' => Some('\x1b'),
+            'f' => Some('\x0c'),
+            'n' => Some('\n'),
+            'r' => Some('\r'),
+            't' => Some('\t'),
+            'v' => Some('\x0b'),
+            '\\' | '\'' | '"' | '?' => Some(next),
+            _ => None,
+        };
+        if let Some(ch) = simple {
+            result.push(ch);
+            continue;
+        }
+        let code = match next {
+            '0'..='7' => {
+                i -= 1;
+                escape_digits(chars, &mut i, 8, 3)
+            }
+            'x' => escape_digits(chars, &mut i, 16, 2),
+            'u' => escape_digits(chars, &mut i, 16, 4),
+            'U' => escape_digits(chars, &mut i, 16, 8),
+            'c' if i < chars.len() => {
+                i += 1;
+                Some((chars[i - 1].to_ascii_uppercase() as u32) ^ 0x40)
+            }
+            _ => {
+                result.push('\\');
+                result.push(next);
+                continue;
+            }
+        };
+        match code.and_then(char::from_u32) {
+            Some(ch) => result.push(ch),
+            // an escape without digits stays as it is
+            None => {
+                result.push('\\');
+                result.push(next);
+            }
+        }
+    }
+
+    result
+}
+
+// The value of the digits of a numeric escape at `i`, at most `max` of them
+fn escape_digits(chars: &[char], i: &mut usize, radix: u32, max: usize) -> Option<u32> {
+    let len = chars[*i..]
+        .iter()
+        .take(max)
+        .take_while(|c| c.is_digit(radix))
+        .count();
+    let digits: String = chars[*i..*i + len].iter().collect();
+    *i += len;
+    u32::from_str_radix(&digits, radix).ok()
+}
+
+// Find the quote closing the one at `start`. Between single quotes a backslash
+// is literal, except in a `$'...'` string where it escapes the next character.
+pub fn find_closing_quote(chars: &[char], start: usize, quote_char: char) -> Option<usize> {
+    let ansi_c = quote_char == '\'' && start > 0 && chars[start - 1] == '$' && !is_escaped(chars, start - 1);
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        if ansi_c && chars[i] == '\\' {
+            i += 2;
+            continue;
+        }
+        if chars[i] == quote_char && (quote_char == '\'' || !is_escaped(chars, i)) {
+            return Some(i);
+        }
+        i += 1;
+    }
+
+    None
+}
+
+// Like find_closing_quote for `"`, skipping the command substitutions inside
+// since they may hold quotes of their own
+pub fn find_closing_double_quote(chars: &[char], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                i += 1;
+            }
+            '"' => {
+                return Some(i);
+            }
+            '$' if chars.get(i + 1) == Some(&'(') => {
+                i = find_substitution_end(chars, i)?;
+            }
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                i = find_parameter_end(chars, i)?;
+            }
+            '`' => {
+                i = find_closing_backtick(chars, i)?;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    None
+}
+
+// Find the `}` closing the `${` at `start`, the word of an operator like
+// `${VAR:-word}` may hold quotes, substitutions and braces of its own
+pub fn find_parameter_end(chars: &[char], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = start + 2;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                i += 1;
+            }
+            '\'' => {
+                i = find_closing_quote(chars, i, '\'')?;
+            }
+            '"' => {
+                i = find_closing_double_quote(chars, i)?;
+            }
+            '`' => {
+                i = find_closing_backtick(chars, i)?;
+            }
+            '$' if chars.get(i + 1) == Some(&'(') => {
+                i = find_substitution_end(chars, i)?;
+            }
+            '{' => {
+                depth += 1;
+            }
+            '}' if depth == 0 => {
+                return Some(i);
+            }
+            '}' => {
+                depth -= 1;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    None
+}
+
+// The index of the `]` closing the `[` at `start`
+pub fn find_subscript_end(chars: &[char], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, ch) in chars.iter().enumerate().skip(start) {
+        match ch {
+            '[' => depth += 1,
+            ']' if depth == 1 => {
+                return Some(idx);
+            }
+            ']' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+// Find the `)` closing the `$(` at `start`, parentheses in the command are
+// counted unless quoted
+pub fn find_substitution_end(chars: &[char], start: usize) -> Option<usize> {
+    find_closing_paren(chars, start + 1)
+}
+
+// Find the `)` matching the `(` at `open`, skipping quoted parts and nested
+// substitutions
+pub fn find_closing_paren(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = open + 1;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                i += 1;
+            }
+            '\'' => {
+                i = find_closing_quote(chars, i, '\'')?;
+            }
+            '"' => {
+                i = find_closing_double_quote(chars, i)?;
+            }
+            '`' => {
+                i = find_closing_backtick(chars, i)?;
+            }
+            '$' if chars.get(i + 1) == Some(&'(') => {
+                i = find_substitution_end(chars, i)?;
+            }
+            '(' => {
+                depth += 1;
+            }
+            ')' if depth == 0 => {
+                return Some(i);
+            }
+            ')' => {
+                depth -= 1;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    None
+}
+
+// Backquotes do not nest, an inner one has to be escaped
+pub fn find_closing_backtick(chars: &[char], start: usize) -> Option<usize> {
     let mut i = start + 1;
 
     while i < chars.len() {
-        if chars[i] == quote_char {
-            // Check if it's escaped
-            if !is_escaped(chars, i) {
+        match chars[i] {
+            '\\' => {
+                i += 1;
+            }
+            '`' => {
                 return Some(i);
             }
+            _ => (),
         }
         i += 1;
     }
@@ -227,11 +806,13 @@ pub fn collect_data(
     is_all: bool,
     _is_classify: bool,
     _is_listing: bool,
-    dirs: Vec<String>
+    dirs: Vec<String>,
+    token: &CancellationToken
 ) -> CrateResult<Vec<Directory>> {
     let mut results: Vec<Directory> = Vec::new();
 
-    for dir in dirs {
+    for (idx, dir) in dirs.iter().enumerate() {
+        let dir = dir.clone();
         let display_name = &dir;
         let current_path = pwd();
         let target_dir_path = join_path(&current_path, &dir);
@@ -284,6 +865,18 @@ pub fn collect_data(
 
                 if let Ok(read_dir) = fs::read_dir(&target_dir_path) {
                     for ent_res in read_dir {
+                        if token.is_cancelled() {
+                            let read: usize = results
+                                .iter()
+                                .map(|d: &Directory| d.file_content.len())
+                                .sum();
+                            return Err(
+                                ShellError::interrupted(
+                                    format!("reading {} entries", read + entries.len()),
+                                    &dirs[idx..]
+                                ).into()
+                            );
+                        }
                         if let Ok(ent) = ent_res {
                             let name = ent.file_name().to_string_lossy().to_string();
 
@@ -641,6 +1234,37 @@ fn resolve_unix_user(uid: u32) -> String {
     }
 }
 
+// The home directory of a user from the passwd database. `getpwnam_r` fills
+// a buffer of ours, unlike `getpwnam` it is safe to call from any thread.
+#[cfg(unix)]
+pub fn user_home(name: &str) -> Option<String> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        unsafe {
+            let mut pwd: libc::passwd = std::mem::zeroed();
+            let mut result = std::ptr::null_mut();
+            let err = libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result
+            );
+            // the entry does not fit, try again with more room
+            if err == libc::ERANGE && buffer.len() < 1 << 20 {
+                buffer.resize(buffer.len() * 2, 0);
+                continue;
+            }
+            if err != 0 || result.is_null() || pwd.pw_dir.is_null() {
+                return None;
+            }
+            let c_str = std::ffi::CStr::from_ptr(pwd.pw_dir);
+            return Some(c_str.to_string_lossy().into_owned());
+        }
+    }
+}
+
 #[cfg(unix)]
 fn resolve_unix_group(gid: u32) -> String {
     unsafe {
@@ -681,3 +1305,203 @@ pub fn pwd() -> String {
     let cur_dir = std::env::current_dir().unwrap();
     cur_dir.display().to_string()
 }
+
+// Resolve a program name the way execvp does: names containing a slash are
+// taken as paths, everything else is searched in the `path` directories.
+pub fn find_in_path(program: &str, path: Option<&str>) -> Option<PathBuf> {
+    if program.is_empty() {
+        return None;
+    }
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return if is_executable(&path) { Some(path) } else { None };
+    }
+    std::env
+        ::split_paths(path?)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(md) => md.is_file() && md.mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        let (tokens, status) = process_shell_quotes(input);
+        assert_eq!(status, QuoteStatus::Balanced, "{}", input);
+        tokens
+    }
+
+    fn word(text: &str) -> Token {
+        Token::Word(text.to_string())
+    }
+
+    #[test]
+    fn pipes_split_words() {
+        assert_eq!(tokens("ls -l | wc"), [word("ls"), word("-l"), Token::Pipe, word("wc")]);
+        assert_eq!(tokens("a|b"), [word("a"), Token::Pipe, word("b")]);
+        assert_eq!(tokens(""), []);
+    }
+
+    #[test]
+    fn quotes_stay_in_words() {
+        assert_eq!(tokens("echo 'a | b'"), [word("echo"), word("'a | b'")]);
+        assert_eq!(tokens("echo \"x y\"z"), [word("echo"), word("\"x y\"z")]);
+        assert_eq!(tokens("echo a\\|b"), [word("echo"), word("a\\|b")]);
+    }
+
+    #[test]
+    fn list_operators() {
+        assert_eq!(
+            tokens("a;b && c||d\ne"),
+            [
+                word("a"),
+                Token::Semi,
+                word("b"),
+                Token::And,
+                word("c"),
+                Token::Or,
+                word("d"),
+                Token::Newline,
+                word("e"),
+            ]
+        );
+        assert_eq!(tokens("echo 'a;b' \"&&\""), [word("echo"), word("'a;b'"), word("\"&&\"")]);
+    }
+
+    #[test]
+    fn case_operators() {
+        assert_eq!(
+            tokens("(a|b) x;; c) y;& *) z;;&"),
+            [
+                Token::LeftParen,
+                word("a"),
+                Token::Pipe,
+                word("b"),
+                Token::RightParen,
+                word("x"),
+                Token::DoubleSemi,
+                word("c"),
+                Token::RightParen,
+                word("y"),
+                Token::SemiAmp,
+                word("*"),
+                Token::RightParen,
+                word("z"),
+                Token::DoubleSemiAmp,
+            ]
+        );
+        // parentheses of substitutions and quotes stay in the word
+        assert_eq!(tokens("a$(b) '(c)' \\(d"), [word("a$(b)"), word("'(c)'"), word("\\(d")]);
+    }
+
+    #[test]
+    fn redirections() {
+        assert_eq!(
+            tokens("cmd 2>err >>log"),
+            [
+                word("cmd"),
+                Token::Redirect(2, RedirectKind::Output),
+                word("err"),
+                Token::Redirect(1, RedirectKind::Append),
+                word("log"),
+            ]
+        );
+        assert_eq!(tokens("a2>&1"), [word("a2"), Token::Redirect(1, RedirectKind::Duplicate), word("1")]);
+        assert_eq!(tokens("&> all"), [Token::Redirect(1, RedirectKind::OutputAll), word("all")]);
+        assert_eq!(tokens("echo 4294967295<x")[1], Token::Redirect(u32::MAX, RedirectKind::Input));
+        let err = tokenize("echo 99999999999999>x").unwrap_err();
+        assert_eq!(err.to_string(), "99999999999999: bad file descriptor");
+    }
+
+    #[test]
+    fn here_documents() {
+        // the body takes the place of the delimiter
+        assert_eq!(
+            tokens("cat <<EOF | wc\nline 1\nline 2\nEOF\n"),
+            [
+                word("cat"),
+                Token::Redirect(0, RedirectKind::HereDoc),
+                word("line 1\nline 2\n"),
+                Token::Pipe,
+                word("wc"),
+                Token::Newline,
+            ]
+        );
+        assert_eq!(
+            tokens("cat <<-'END'\n\tx\n\tEND\n"),
+            [word("cat"), Token::Redirect(0, RedirectKind::HereDocQuoted), word("x\n"), Token::Newline]
+        );
+        assert_eq!(tokens("cat <<< word"), [word("cat"), Token::Redirect(0, RedirectKind::HereString), word("word")]);
+    }
+
+    #[test]
+    fn unfinished_input() {
+        assert_eq!(process_shell_quotes("echo 'a").1, QuoteStatus::UnclosedSingle);
+        assert_eq!(process_shell_quotes("echo \"a").1, QuoteStatus::UnclosedDouble);
+        assert_eq!(process_shell_quotes("cat <<EOF\nbody").1, QuoteStatus::UnclosedHereDoc);
+        assert_eq!(process_shell_quotes("echo $(ls").1, QuoteStatus::UnclosedSubstitution(')'));
+        assert_eq!(process_shell_quotes("echo `ls").1, QuoteStatus::UnclosedSubstitution('`'));
+        assert_eq!(process_shell_quotes("echo ${x").1, QuoteStatus::UnclosedSubstitution('}'));
+        // the error names what is missing
+        let error = |input| tokenize(input).unwrap_err().to_string();
+        assert_eq!(error("echo ${x"), "unexpected EOF while looking for matching `}'");
+        assert_eq!(error("echo $(ls"), "unexpected EOF while looking for matching `)'");
+        assert_eq!(error("echo 'a"), "unexpected EOF while looking for matching `''");
+        assert_eq!(error("echo \"a"), "unexpected EOF while looking for matching `\"'");
+    }
+
+    #[test]
+    fn quote_removal() {
+        assert_eq!(unquote_word("'a | b'"), "a | b");
+        assert_eq!(unquote_word("\"x y\"z"), "x yz");
+        assert_eq!(unquote_word("a\\\"b"), "a\"b");
+        // single quotes keep backslashes, double quotes only some escapes
+        assert_eq!(unquote_word("'a\\nb'"), "a\\nb");
+        assert_eq!(unquote_word("\"\\$x \\n\""), "$x \\n");
+        assert_eq!(unquote_word("\"it's\""), "it's");
+        assert_eq!(unquote_word("\\n\\'"), "n'");
+        assert_eq!(unquote_word("$'a\\tb'c"), "a\tbc");
+    }
+
+    #[test]
+    fn ansi_c_escapes() {
+        let decode = |text: &str| decode_ansi_c(&text.chars().collect::<Vec<_>>());
+        assert_eq!(decode("a\\nb\\tc"), "a\nb\tc");
+        assert_eq!(decode("\\e[0m\\a"), "\x1b[0m\x07");
+        assert_eq!(decode("\\101\\0\\x41\\x4a"), "A\0AJ");
+        assert_eq!(decode("\\u00e9\\U0001F600"), "\u{e9}\u{1f600}");
+        assert_eq!(decode("\\cA\\c["), "\x01\x1b");
+        assert_eq!(decode("\\'\\\"\\\\"), "'\"\\");
+        // unknown escapes and ones without digits stay as they are
+        assert_eq!(decode("\\q\\x\\"), "\\q\\x\\");
+    }
+
+    #[test]
+    fn ansi_c_strings_stay_in_words() {
+        assert_eq!(tokens("echo $'it\\'s' x"), [word("echo"), word("$'it\\'s'"), word("x")]);
+        assert_eq!(tokens("echo $'a;b'"), [word("echo"), word("$'a;b'")]);
+        assert_eq!(process_shell_quotes("echo $'a\\'").1, QuoteStatus::UnclosedSingle);
+    }
+
+    #[test]
+    fn line_continuation() {
+        assert_eq!(tokens("echo a\\\nb c"), [word("echo"), word("ab"), word("c")]);
+        assert_eq!(process_shell_quotes("echo a \\").1, QuoteStatus::Continued);
+        assert_eq!(tokens("echo 'a\\'"), [word("echo"), word("'a\\'")]);
+    }
+
+    #[test]
+    fn home_directories() {
+        assert_eq!(user_home("root").as_deref(), Some("/root"));
+        assert_eq!(user_home("no such user"), None);
+        assert_eq!(user_home("a\0b"), None);
+    }
+}
@@ -0,0 +1,278 @@
+use anyhow::anyhow;
+use crate::errors::{ CrateResult, ShellError };
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    Done(i32),
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub id: usize,
+    // process group of the job, 0 when job control is off and the processes
+    // share the group of the shell
+    pub pgid: i32,
+    // every process of the job with its status once it has finished
+    pub processes: Vec<(i32, Option<i32>)>,
+    pub command: String,
+    pub state: JobState,
+    // signal that terminated the last process, if any
+    pub killed_by: Option<i32>,
+}
+
+impl Job {
+    pub fn new(pgid: i32, pids: Vec<i32>, command: String) -> Self {
+        Self {
+            id: 0,
+            pgid,
+            processes: pids
+                .into_iter()
+                .map(|pid| (pid, None))
+                .collect(),
+            command,
+            state: JobState::Running,
+            killed_by: None,
+        }
+    }
+
+    pub fn last_pid(&self) -> i32 {
+        self.processes.last().map_or(0, |(pid, _)| *pid)
+    }
+
+    pub fn signal(&self, signal: i32) {
+        unsafe {
+            if self.pgid > 0 {
+                libc::kill(-self.pgid, signal);
+            } else {
+                for (pid, _) in &self.processes {
+                    libc::kill(*pid, signal);
+                }
+            }
+        }
+    }
+
+    // Block until every process has finished or the job got stopped
+    pub fn wait(&mut self) {
+        let last_pid = self.last_pid();
+        for (pid, status) in self.processes.iter_mut().filter(|(_, status)| status.is_none()) {
+            loop {
+                let mut raw = 0;
+                let res = unsafe { libc::waitpid(*pid, &mut raw, libc::WUNTRACED) };
+                if res == -1 {
+                    if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    // already reaped somewhere else, nothing more to learn
+                    *status = Some(0);
+                } else if libc::WIFSTOPPED(raw) {
+                    self.state = JobState::Stopped;
+                    return;
+                } else {
+                    if *pid == last_pid && libc::WIFSIGNALED(raw) {
+                        self.killed_by = Some(libc::WTERMSIG(raw));
+                    }
+                    *status = Some(decode_status(raw));
+                }
+                break;
+            }
+        }
+        self.finish_if_done();
+    }
+
+    // Collect state changes without blocking
+    fn poll(&mut self) {
+        for (pid, status) in self.processes.iter_mut().filter(|(_, status)| status.is_none()) {
+            let mut raw = 0;
+            let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+            match unsafe { libc::waitpid(*pid, &mut raw, flags) } {
+                0 => (),
+                -1 => {
+                    *status = Some(0);
+                }
+                _ if libc::WIFSTOPPED(raw) => {
+                    self.state = JobState::Stopped;
+                }
+                _ if libc::WIFCONTINUED(raw) => {
+                    self.state = JobState::Running;
+                }
+                _ => {
+                    *status = Some(decode_status(raw));
+                }
+            }
+        }
+        self.finish_if_done();
+    }
+
+    fn finish_if_done(&mut self) {
+        if self.processes.iter().all(|(_, status)| status.is_some()) {
+            let status = self.processes.last().and_then(|(_, status)| *status).unwrap_or(0);
+            self.state = JobState::Done(status);
+        }
+    }
+}
+
+fn decode_status(raw: i32) -> i32 {
+    if libc::WIFEXITED(raw) {
+        libc::WEXITSTATUS(raw)
+    } else if libc::WIFSIGNALED(raw) {
+        128 + libc::WTERMSIG(raw)
+    } else {
+        0
+    }
+}
+
+// Jobs ordered from the least to the most recently used, the last one is the
+// current job (`%+`) and the one before it the previous job (`%-`)
+#[derive(Debug, Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+}
+
+impl JobTable {
+    // Put the job back in the table as the current job, new jobs get the next free number
+    pub fn add(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id =
+                self.jobs
+                    .iter()
+                    .map(|j| j.id)
+                    .max()
+                    .unwrap_or(0) + 1;
+        }
+        let id = job.id;
+        self.jobs.push(job);
+        id
+    }
+
+    // Remove the job named by a `%n`, `n`, `%+`, `%%` or `%-` spec, by default the current one
+    pub fn take(&mut self, spec: Option<&str>) -> CrateResult<Job> {
+        let idx = match spec.map(|s| s.strip_prefix('%').unwrap_or(s)) {
+            None | Some("") | Some("+") | Some("%") => self.jobs.len().checked_sub(1),
+            Some("-") => self.jobs.len().checked_sub(2),
+            Some(n) =>
+                match n.parse::<usize>() {
+                    Ok(id) => self.jobs.iter().position(|job| job.id == id),
+                    Err(_) => {
+                        return Err(ShellError::Usage(format!("{}: no such job", n)).into());
+                    }
+                }
+        };
+        match idx {
+            Some(idx) => Ok(self.jobs.remove(idx)),
+            None => Err(anyhow!("{}: no such job", spec.unwrap_or("current"))),
+        }
+    }
+
+    pub fn update(&mut self) {
+        for job in self.jobs.iter_mut() {
+            job.poll();
+        }
+    }
+
+    fn marker(&self, idx: usize) -> char {
+        match self.jobs.len() - idx {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        }
+    }
+
+    fn describe(&self, idx: usize) -> String {
+        let job = &self.jobs[idx];
+        let state = match job.state {
+            JobState::Running => String::from("Running"),
+            JobState::Stopped => String::from("Stopped"),
+            JobState::Done(0) => String::from("Done"),
+            JobState::Done(code) => format!("Exit {}", code),
+        };
+        format!("[{}]{}  {:<24}{}", job.id, self.marker(idx), state, job.command)
+    }
+
+    // The `jobs` listing, sorted by job number
+    pub fn list(&mut self) -> String {
+        self.update();
+        let mut order: Vec<usize> = (0..self.jobs.len()).collect();
+        order.sort_by_key(|&idx| self.jobs[idx].id);
+        let mut res = String::new();
+        for idx in order {
+            res.push_str(&self.describe(idx));
+            res.push('\n');
+        }
+        self.jobs.retain(|job| !matches!(job.state, JobState::Done(_)));
+        res
+    }
+
+    // Lines announcing the jobs that finished since the last prompt, which are
+    // then forgotten
+    pub fn notifications(&mut self) -> Vec<String> {
+        self.update();
+        let lines = (0..self.jobs.len())
+            .filter(|&idx| matches!(self.jobs[idx].state, JobState::Done(_)))
+            .map(|idx| self.describe(idx))
+            .collect();
+        self.jobs.retain(|job| !matches!(job.state, JobState::Done(_)));
+        lines
+    }
+}
+
+// The controlling terminal of an interactive shell, handed to foreground jobs
+pub struct Terminal {
+    shell_pgid: i32,
+    modes: libc::termios,
+}
+
+impl Terminal {
+    // Put the shell in its own process group in the foreground of the
+    // terminal. Returns None when stdin is not a terminal.
+    pub fn acquire() -> Option<Self> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return None;
+            }
+            // wait until we are in the foreground, like any job would
+            while libc::tcgetpgrp(libc::STDIN_FILENO) != libc::getpgrp() {
+                libc::kill(-libc::getpgrp(), libc::SIGTTIN);
+            }
+            // the shell must survive the job control signals of the terminal
+            for signal in [libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+            let shell_pgid = libc::getpid();
+            // fails for a session leader, which already owns its group
+            libc::setpgid(shell_pgid, shell_pgid);
+            let shell_pgid = libc::getpgrp();
+            libc::tcsetpgrp(libc::STDIN_FILENO, shell_pgid);
+            let mut modes: libc::termios = std::mem::zeroed();
+            libc::tcgetattr(libc::STDIN_FILENO, &mut modes);
+            Some(Self { shell_pgid, modes })
+        }
+    }
+
+    pub fn give_to(&self, pgid: i32) {
+        if pgid > 0 {
+            unsafe {
+                libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+            }
+        }
+    }
+
+    // Take the terminal back with the modes the shell had before the job ran
+    pub fn reclaim(&self) {
+        unsafe {
+            libc::tcsetpgrp(libc::STDIN_FILENO, self.shell_pgid);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.modes);
+        }
+    }
+}
+
+// Called in the forked child before exec: undo the dispositions the shell set
+// for itself, ignored signals would otherwise stay ignored in the program
+pub fn reset_child_signals() {
+    unsafe {
+        for signal in [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+}
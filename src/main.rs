@@ -1,73 +1,172 @@
 use anyhow::Result;
-use tokio::io::{ self, AsyncBufReadExt, AsyncWriteExt};
+use tokio::io::{ self, AsyncBufRead, AsyncBufReadExt, AsyncWriteExt};
 use tokio::task::JoinHandle;
 
+mod arithmetic;
+mod braces;
 mod command;
 mod errors;
 mod executor;
+mod expand;
+mod glob;
 mod helpers;
-use command::Command;
-use errors::CrateResult;
+mod jobs;
+mod options;
+mod parser;
+mod pattern;
+mod redirection;
+mod signals;
+mod variables;
+use command::CommandList;
+use errors::{ exit_code, CrateResult, ShellError };
 use executor::Executor;
-use helpers::handle_quotes;
+use helpers::{ handle_quotes, InputLines };
 
-fn spawn_user_input_handle() -> JoinHandle<CrateResult<()>> {
+// Where the commands come from: `own_shell -c command [name [args...]]`, a
+// script with `own_shell script [args...]`, or the standard input. The shell
+// is interactive only when reading a terminal.
+async fn open_input(executor: &mut Executor) -> CrateResult<(InputLines, bool)> {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>().into_iter();
+    let input: Box<dyn AsyncBufRead + Unpin + Send> = match args.next() {
+        Some(flag) if flag == "-c" => {
+            let Some(command) = args.next() else {
+                return Err(ShellError::Usage("-c: option requires an argument".into()).into());
+            };
+            if let Some(name) = args.next() {
+                executor.script_name = name;
+            }
+            Box::new(io::BufReader::new(std::io::Cursor::new(command.into_bytes())))
+        }
+        Some(path) => {
+            let file = match tokio::fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(err) => {
+                    return Err(ShellError::NotFound(format!("{}: {}", path, err)).into());
+                }
+            };
+            executor.script_name = path;
+            Box::new(io::BufReader::new(file))
+        }
+        None => {
+            let interactive = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+            let input: Box<dyn AsyncBufRead + Unpin + Send> = Box::new(io::BufReader::new(io::stdin()));
+            return Ok((input.lines(), interactive));
+        }
+    };
+    executor.positional = args.collect();
+    Ok((input.lines(), false))
+}
+
+fn spawn_user_input_handle() -> JoinHandle<CrateResult<i32>> {
     tokio::spawn(async {
-        let stdin = io::stdin();
         let stdout = io::stdout();
-        let mut reader = io::BufReader::new(stdin).lines();
         let mut stdout = io::BufWriter::new(stdout);
         let mut executor = Executor::new();
-        let mut is_ctrl_d = true;
-        stdout.write(
-            format!("Hello to my own shell programm:\n> {}$ ", executor.current_dir).as_bytes()
-        ).await?;
+        let (mut reader, interactive) = match open_input(&mut executor).await {
+            Ok(input) => input,
+            Err(err) => {
+                eprintln!("own_shell: {}", err);
+                return Ok(exit_code(&err));
+            }
+        };
+        // job control and surviving Ctrl-C are for interactive shells only
+        if interactive {
+            executor.enable_job_control();
+            executor.listen_signals()?;
+        }
+        let prompt = |executor: &Executor| {
+            if interactive { format!("> {}$ ", executor.current_dir) } else { String::new() }
+        };
+        let mut is_ctrl_d = interactive;
+        if interactive {
+            stdout.write_all(b"Hello to my own shell programm:\n").await?;
+        }
+        stdout.write_all(prompt(&executor).as_bytes()).await?;
         stdout.flush().await?;
-        while let Ok(Some(line)) = reader.next_line().await {
+        let signals = executor.signals.clone();
+        loop {
+            // Ctrl-C drops the line being typed, including its continuation lines
+            let line = tokio::select! {
+                line = reader.next_line() => Some(line),
+                _ = signals.interrupted() => None,
+            };
+            let line = match line {
+                Some(Ok(Some(line))) => line,
+                Some(_) => break,
+                None => {
+                    executor.last_status = 130;
+                    stdout.write_all(format!("\n{}", prompt(&executor)).as_bytes()).await?;
+                    stdout.flush().await?;
+                    continue;
+                }
+            };
             let input = line.as_str();
             // Get the complete input with closed quotes
-            let complete_input = match handle_quotes(input, &mut stdout).await {
-                Ok(complete) => complete,
-                Err(e) => {
-                    stdout.write(format!("Error reading input: {}\n", e).as_bytes()).await?;
+            let complete_input = tokio::select! {
+                complete = handle_quotes(input, &mut reader, &mut stdout, interactive) => Some(complete),
+                _ = signals.interrupted() => None,
+            };
+            let complete_input = match complete_input {
+                Some(Ok(complete)) => complete,
+                None => {
+                    executor.last_status = 130;
+                    stdout.write_all(format!("\n{}", prompt(&executor)).as_bytes()).await?;
+                    stdout.flush().await?;
+                    continue;
+                }
+                Some(Err(e)) if !interactive => {
+                    stdout.flush().await?;
+                    eprintln!("own_shell: {}: {}", executor.script_name, e);
+                    executor.last_status = 2;
+                    break;
+                }
+                Some(Err(e)) => {
+                    stdout.write_all(format!("Error reading input: {}\n", e).as_bytes()).await?;
                     continue;
                 }
             };
             match complete_input {
-                v if v.len() == 0 => (),
-                processed_input =>
-                    match Command::try_from(processed_input) {
-                        Ok(command) =>
-                            match executor.execute(&command).await {
-                                anyhow::Result::Ok(res) => {
-                                    stdout.write(res.as_bytes()).await?;
-                                    if command == Command::Exit {
-                                        is_ctrl_d = false;
-                                        break;
-                                    }
-                                }
-                                anyhow::Result::Err(error) => {
-                                    stdout.write(format!("Error: {}\n", error).as_bytes()).await?;
-                                }
+                v if v.is_empty() => (),
+                tokens =>
+                    match CommandList::try_from(tokens) {
+                        Ok(list) => {
+                            executor.execute_list(&list).await;
+                            if executor.is_exit {
+                                is_ctrl_d = false;
+                                break;
                             }
+                        }
+                        // a script stops at its first syntax error
+                        Err(err) if !interactive => {
+                            stdout.flush().await?;
+                            eprintln!("own_shell: {}: {}", executor.script_name, err);
+                            executor.last_status = 2;
+                            break;
+                        }
                         Err(err) => {
-                            stdout.write(format!("Error: {}\n", err).as_bytes()).await?;
+                            executor.last_status = 2;
+                            stdout.write_all(format!("Error: {}\n", err).as_bytes()).await?;
                         }
                     }
             }
-            stdout.write(format!("> {}$ ", executor.current_dir).as_bytes()).await?;
+            // report background jobs that finished while the command ran
+            for line in executor.jobs.notifications() {
+                stdout.write_all(format!("{}\n", line).as_bytes()).await?;
+            }
+            stdout.write_all(prompt(&executor).as_bytes()).await?;
             stdout.flush().await?;
         }
         if is_ctrl_d {
-            stdout.write(b"\n").await?;
+            stdout.write_all(b"\n").await?;
         }
 
         stdout.flush().await?;
-        Ok(())
+        Ok(executor.last_status)
     })
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    spawn_user_input_handle().await?
+    let status = spawn_user_input_handle().await??;
+    std::process::exit(status)
 }
@@ -0,0 +1,51 @@
+// The options of the `shopt` builtin, all off by default
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    // patterns also match names starting with a dot
+    pub dotglob: bool,
+    // the `?(..)`, `*(..)`, `+(..)`, `@(..)` and `!(..)` pattern groups
+    pub extglob: bool,
+    // a pattern without matches is an error
+    pub failglob: bool,
+    // `**` matches any number of directories in pathname expansion
+    pub globstar: bool,
+    // a pattern without matches expands to nothing instead of itself
+    pub nullglob: bool,
+}
+
+impl Options {
+    pub const NAMES: [&'static str; 5] = [
+        "dotglob",
+        "extglob",
+        "failglob",
+        "globstar",
+        "nullglob",
+    ];
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "dotglob" => Some(self.dotglob),
+            "extglob" => Some(self.extglob),
+            "failglob" => Some(self.failglob),
+            "globstar" => Some(self.globstar),
+            "nullglob" => Some(self.nullglob),
+            _ => None,
+        }
+    }
+
+    // Returns false for an unknown name
+    pub fn set(&mut self, name: &str, on: bool) -> bool {
+        let option = match name {
+            "dotglob" => &mut self.dotglob,
+            "extglob" => &mut self.extglob,
+            "failglob" => &mut self.failglob,
+            "globstar" => &mut self.globstar,
+            "nullglob" => &mut self.nullglob,
+            _ => {
+                return false;
+            }
+        };
+        *option = on;
+        true
+    }
+}
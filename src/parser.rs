@@ -0,0 +1,618 @@
+use anyhow::anyhow;
+use crate::command::{
+    arithmetic_command,
+    AndOr,
+    ArithmeticFor,
+    Case,
+    CaseEnd,
+    CaseItem,
+    CommandList,
+    Compound,
+    Connector,
+    For,
+    If,
+    Pipeline,
+    Redirect,
+    SimpleCommand,
+    Stage,
+    While,
+};
+use crate::errors::CrateResult;
+use crate::helpers::{ RedirectKind, Token };
+use crate::variables::is_valid_name;
+
+// Recursive descent over the tokens of an input:
+//   list     := and_or ((`;` | `&` | newline) and_or)*
+//   and_or   := pipeline ((`&&` | `||`) newline* pipeline)*
+//   pipeline := stage (`|` newline* stage)*
+//   stage    := compound redirect* | simple
+//   compound := `if` list `then` list (`elif` list `then` list)* (`else` list)? `fi`
+//             | (`while` | `until`) list do_group
+//             | `for` name ((newline* `in` word*)? (`;` | newline))? newline* do_group
+//             | `for` `((` init `;` condition `;` step `))` (`;` | newline)* do_group
+//             | `case` word newline* `in` newline* case_item* `esac`
+//   do_group := `do` list `done`
+//   case_item := `(`? word (`|` word)* `)` list (`;;` | `;&` | `;;&`)? newline*
+// The lists of a compound command end at the reserved word that follows them,
+// those of a case item at the operator ending it.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    fn unexpected(&self) -> anyhow::Error {
+        match self.peek() {
+            Some(token) => anyhow!("syntax error near unexpected token `{}'", token),
+            None => anyhow!("syntax error near unexpected token `newline'"),
+        }
+    }
+
+    // The reserved word at the current token, if it is one
+    fn keyword(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(word)) if RESERVED.contains(&word.as_str()) => Some(word),
+            _ => None,
+        }
+    }
+
+    // Skip the reserved word `expected`, more input may still bring it
+    fn expect(&mut self, expected: &str) -> CrateResult<()> {
+        match self.keyword() {
+            Some(keyword) if keyword == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ if self.peek().is_none() => Err(Incomplete.into()),
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    // A list ending at one of the reserved words `ends`, which must not be empty
+    fn list_until(&mut self, ends: &[&str]) -> CrateResult<CommandList> {
+        let list = self.list(ends)?;
+        if self.peek().is_none() {
+            return Err(Incomplete.into());
+        }
+        if list.items.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(list)
+    }
+
+    fn list(&mut self, ends: &[&str]) -> CrateResult<CommandList> {
+        let mut list = CommandList::default();
+        loop {
+            self.skip_newlines();
+            if self.peek().is_none() || self.keyword().is_some_and(|word| ends.contains(&word)) {
+                break;
+            }
+            if self.case_end().is_some() {
+                break;
+            }
+            list.items.push(self.and_or()?);
+            match self.peek() {
+                Some(Token::Semi) | Some(Token::Newline) => {
+                    self.pos += 1;
+                }
+                Some(Token::Amp) => {
+                    if let Some(and_or) = list.items.last_mut() {
+                        and_or.background = true;
+                    }
+                    self.pos += 1;
+                }
+                None => break,
+                Some(_) if self.case_end().is_some() => break,
+                Some(Token::Word(_)) if self.keyword().is_some_and(|word| ends.contains(&word)) => {
+                    break;
+                }
+                Some(_) => {
+                    return Err(self.unexpected());
+                }
+            }
+        }
+        Ok(list)
+    }
+
+    fn and_or(&mut self) -> CrateResult<AndOr> {
+        let first = self.pipeline()?;
+        let mut rest = vec![];
+        loop {
+            let connector = match self.peek() {
+                Some(Token::And) => Connector::And,
+                Some(Token::Or) => Connector::Or,
+                _ => break,
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            if self.peek().is_none() {
+                return Err(Incomplete.into());
+            }
+            rest.push((connector, self.pipeline()?));
+        }
+        Ok(AndOr { first, rest, background: false })
+    }
+
+    fn pipeline(&mut self) -> CrateResult<Pipeline> {
+        let mut commands = vec![self.stage()?];
+        while self.peek() == Some(&Token::Pipe) {
+            self.pos += 1;
+            self.skip_newlines();
+            if self.peek().is_none() {
+                return Err(Incomplete.into());
+            }
+            commands.push(self.stage()?);
+        }
+        Ok(Pipeline { commands })
+    }
+
+    fn stage(&mut self) -> CrateResult<Stage> {
+        let compound = match self.keyword() {
+            Some("if") => Compound::If(self.if_clause()?),
+            Some(keyword @ ("while" | "until")) => {
+                let until = keyword == "until";
+                self.pos += 1;
+                let condition = self.list_until(&["do"])?;
+                let body = self.do_group()?;
+                Compound::While(While { condition, body, until })
+            }
+            Some("for") => self.for_clause()?,
+            Some("case") => Compound::Case(self.case_clause()?),
+            // the other reserved words only end a compound command
+            Some(_) => {
+                return Err(self.unexpected());
+            }
+            None => {
+                return Ok(Stage::Simple(self.simple_command()?));
+            }
+        };
+        let mut redirects = vec![];
+        while let Some(&Token::Redirect(fd, kind)) = self.peek() {
+            self.pos += 1;
+            redirects.push(self.redirect(fd, kind)?);
+        }
+        // a compound command is followed by an operator or a reserved word
+        if let (Some(Token::Word(_)), None) = (self.peek(), self.keyword()) {
+            return Err(self.unexpected());
+        }
+        Ok(Stage::Compound(compound, redirects))
+    }
+
+    fn if_clause(&mut self) -> CrateResult<If> {
+        let mut clause = If { branches: vec![], otherwise: None };
+        let mut keyword = "if";
+        while keyword != "fi" {
+            self.pos += 1;
+            if keyword == "else" {
+                clause.otherwise = Some(self.list_until(&["fi"])?);
+                keyword = "fi";
+                continue;
+            }
+            let condition = self.list_until(&["then"])?;
+            self.expect("then")?;
+            let body = self.list_until(&["elif", "else", "fi"])?;
+            clause.branches.push((condition, body));
+            keyword = match self.keyword() {
+                Some("elif") => "elif",
+                Some("else") => "else",
+                _ => "fi",
+            };
+        }
+        self.expect("fi")?;
+        Ok(clause)
+    }
+
+    fn for_clause(&mut self) -> CrateResult<Compound> {
+        self.pos += 1;
+        let name = match self.peek() {
+            Some(Token::Word(word)) => word.clone(),
+            None => {
+                return Err(Incomplete.into());
+            }
+            _ => {
+                return Err(self.unexpected());
+            }
+        };
+        if let Some(expression) = arithmetic_command(&name) {
+            let parts: Vec<&str> = expression.split(';').collect();
+            let [init, condition, step] = parts[..] else {
+                return Err(anyhow!("syntax error: arithmetic expression required"));
+            };
+            self.pos += 1;
+            while matches!(self.peek(), Some(Token::Semi | Token::Newline)) {
+                self.pos += 1;
+            }
+            let body = self.do_group()?;
+            return Ok(
+                Compound::ArithmeticFor(ArithmeticFor {
+                    init: init.to_string(),
+                    condition: condition.to_string(),
+                    step: step.to_string(),
+                    body,
+                })
+            );
+        }
+        if !is_valid_name(&name) {
+            return Err(anyhow!("`{}': not a valid identifier", name));
+        }
+        self.pos += 1;
+        self.skip_newlines();
+        let mut words = None;
+        if self.peek() == Some(&Token::Word("in".to_string())) {
+            self.pos += 1;
+            let mut list = vec![];
+            while let Some(Token::Word(word)) = self.peek() {
+                list.push(word.clone());
+                self.pos += 1;
+            }
+            words = Some(list);
+            match self.peek() {
+                Some(Token::Semi | Token::Newline) => self.pos += 1,
+                None => {
+                    return Err(Incomplete.into());
+                }
+                _ => {
+                    return Err(self.unexpected());
+                }
+            }
+        } else if self.peek() == Some(&Token::Semi) {
+            self.pos += 1;
+        }
+        self.skip_newlines();
+        let body = self.do_group()?;
+        Ok(Compound::For(For { name, words, body }))
+    }
+
+    fn case_clause(&mut self) -> CrateResult<Case> {
+        self.pos += 1;
+        let word = match self.next() {
+            Some(Token::Word(word)) => word,
+            None => {
+                return Err(Incomplete.into());
+            }
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected());
+            }
+        };
+        self.skip_newlines();
+        self.expect("in")?;
+        let mut items = vec![];
+        loop {
+            self.skip_newlines();
+            if self.keyword() == Some("esac") {
+                break;
+            }
+            if self.peek() == Some(&Token::LeftParen) {
+                self.pos += 1;
+            }
+            let patterns = self.case_patterns()?;
+            let body = self.list(&["esac"])?;
+            let end = match self.case_end() {
+                Some(end) => {
+                    self.pos += 1;
+                    end
+                }
+                // the last item may go without one
+                None if self.keyword() == Some("esac") => CaseEnd::Break,
+                None if self.peek().is_none() => {
+                    return Err(Incomplete.into());
+                }
+                None => {
+                    return Err(self.unexpected());
+                }
+            };
+            items.push(CaseItem { patterns, body, end });
+        }
+        self.expect("esac")?;
+        Ok(Case { word, items })
+    }
+
+    // The patterns of a case item up to its `)`
+    fn case_patterns(&mut self) -> CrateResult<Vec<String>> {
+        let mut patterns = vec![];
+        loop {
+            match self.peek() {
+                Some(Token::Word(pattern)) => patterns.push(pattern.clone()),
+                None => {
+                    return Err(Incomplete.into());
+                }
+                _ => {
+                    return Err(self.unexpected());
+                }
+            }
+            self.pos += 1;
+            match self.next() {
+                Some(Token::Pipe) => (),
+                Some(Token::RightParen) => {
+                    return Ok(patterns);
+                }
+                None => {
+                    return Err(Incomplete.into());
+                }
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected());
+                }
+            }
+        }
+    }
+
+    // The operator ending a case item at the current token, if it is one
+    fn case_end(&self) -> Option<CaseEnd> {
+        match self.peek() {
+            Some(Token::DoubleSemi) => Some(CaseEnd::Break),
+            Some(Token::SemiAmp) => Some(CaseEnd::FallThrough),
+            Some(Token::DoubleSemiAmp) => Some(CaseEnd::Continue),
+            _ => None,
+        }
+    }
+
+    fn do_group(&mut self) -> CrateResult<CommandList> {
+        self.expect("do")?;
+        let body = self.list_until(&["done"])?;
+        self.expect("done")?;
+        Ok(body)
+    }
+
+    fn simple_command(&mut self) -> CrateResult<SimpleCommand> {
+        let mut command = SimpleCommand::default();
+        loop {
+            match self.peek() {
+                Some(Token::Word(_)) => {
+                    if let Some(Token::Word(word)) = self.next() {
+                        command.words.push(word);
+                    }
+                }
+                Some(&Token::Redirect(fd, kind)) => {
+                    self.pos += 1;
+                    command.redirects.push(self.redirect(fd, kind)?);
+                }
+                _ => break,
+            }
+        }
+        if command == SimpleCommand::default() {
+            return Err(self.unexpected());
+        }
+        Ok(command)
+    }
+
+    // The target word of a redirection operator
+    fn redirect(&mut self, fd: u32, kind: RedirectKind) -> CrateResult<Redirect> {
+        match self.peek() {
+            Some(Token::Word(target)) => {
+                let target = target.clone();
+                self.pos += 1;
+                Ok(Redirect { fd, kind, target })
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+// The words that are reserved at the start of a command
+const RESERVED: [&str; 13] = [
+    "if",
+    "then",
+    "elif",
+    "else",
+    "fi",
+    "while",
+    "until",
+    "for",
+    "in",
+    "do",
+    "done",
+    "case",
+    "esac",
+];
+
+// The input ends inside a compound command or right after an operator that
+// needs another command, more lines are needed to complete it
+#[derive(Debug)]
+pub struct Incomplete;
+
+impl std::fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "syntax error: unexpected end of file")
+    }
+}
+
+impl std::error::Error for Incomplete {}
+
+// Whether the tokens make a complete input or more lines belong to them
+pub fn is_incomplete(tokens: &[Token]) -> bool {
+    matches!(CommandList::try_from(tokens.to_vec()), Err(err) if err.is::<Incomplete>())
+}
+
+impl TryFrom<Vec<Token>> for CommandList {
+    type Error = anyhow::Error;
+
+    fn try_from(tokens: Vec<Token>) -> Result<Self, Self::Error> {
+        let mut parser = Parser { tokens, pos: 0 };
+        let list = parser.list(&[])?;
+        // a case item operator outside of a case command
+        if parser.peek().is_some() {
+            return Err(parser.unexpected());
+        }
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::tokenize;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        tokenize(input).unwrap()
+    }
+
+    fn parse(input: &str) -> CrateResult<CommandList> {
+        CommandList::try_from(tokens(input))
+    }
+
+    fn words(pipeline: &Pipeline) -> Vec<Vec<String>> {
+        pipeline.commands
+            .iter()
+            .map(|stage| {
+                match stage {
+                    Stage::Simple(command) => command.words.clone(),
+                    Stage::Compound(compound, _) => vec![compound.to_string()],
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lists() {
+        let list = parse("a ; b \n c").unwrap();
+        assert_eq!(list.items.len(), 3);
+        assert_eq!(words(&list.items[2].first), [["c"]]);
+        assert_eq!(parse("a ;").unwrap().items.len(), 1);
+        assert_eq!(parse("\n \n").unwrap(), CommandList::default());
+    }
+
+    #[test]
+    fn and_or() {
+        let list = parse("a && b | c || d").unwrap();
+        let and_or = &list.items[0];
+        assert_eq!(words(&and_or.first), [["a"]]);
+        assert_eq!(and_or.rest[0].0, Connector::And);
+        assert_eq!(words(&and_or.rest[0].1), [["b"], ["c"]]);
+        assert_eq!(and_or.rest[1].0, Connector::Or);
+        // the next pipeline may start on another line
+        assert_eq!(parse("a && \n b").unwrap().items[0].rest.len(), 1);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |input| parse(input).unwrap_err().to_string();
+        assert_eq!(error("; a"), "syntax error near unexpected token `;'");
+        assert_eq!(error("a && || b"), "syntax error near unexpected token `||'");
+        assert_eq!(error("a | ;"), "syntax error near unexpected token `;'");
+        // more input could still finish it
+        assert_eq!(error("a |"), "syntax error: unexpected end of file");
+        assert_eq!(error("a ; ; b"), "syntax error near unexpected token `;'");
+    }
+
+    #[test]
+    fn if_clauses() {
+        let input = "if a; then b; elif c\nthen d; else e; fi | f > out";
+        let list = parse(input).unwrap();
+        let words = words(&list.items[0].first);
+        assert_eq!(words, [["if a; then b; elif c; then d; else e; fi"], ["f"]]);
+        let Stage::Compound(Compound::If(clause), redirects) = &list.items[0].first.commands[0] else {
+            panic!("not an if clause: {:?}", list);
+        };
+        assert_eq!(clause.branches.len(), 2);
+        assert!(clause.otherwise.is_some());
+        assert!(redirects.is_empty());
+        // reserved words are only recognized where a command starts
+        assert_eq!(parse("echo if then fi").unwrap().to_string(), "echo if then fi");
+    }
+
+    #[test]
+    fn unfinished_commands() {
+        for input in ["if a", "if a; then", "if a; then b\n", "if a; then b; else c", "a &&", "a |"] {
+            assert!(is_incomplete(&tokens(input)), "{}", input);
+        }
+        assert!(!is_incomplete(&tokens("if a; then b; fi")));
+        assert!(!is_incomplete(&tokens("if a; fi")));
+    }
+
+    #[test]
+    fn if_syntax_errors() {
+        let error = |input| parse(input).unwrap_err().to_string();
+        assert_eq!(error("if a; fi"), "syntax error near unexpected token `fi'");
+        assert_eq!(error("if then b; fi"), "syntax error near unexpected token `then'");
+        assert_eq!(error("if a; then fi"), "syntax error near unexpected token `fi'");
+        assert_eq!(error("then"), "syntax error near unexpected token `then'");
+        assert_eq!(error("if a; then b; fi c"), "syntax error near unexpected token `c'");
+    }
+
+    #[test]
+    fn loops() {
+        let shown = |input| parse(input).unwrap().to_string();
+        assert_eq!(shown("while a\ndo b; done"), "while a; do b; done");
+        assert_eq!(shown("until a; b; do c\n done &"), "until a; b; do c; done &");
+        assert_eq!(shown("for x in a 'b c'; do echo $x; done"), "for x in a 'b c'; do echo $x; done");
+        assert_eq!(shown("for x\ndo b; done"), "for x; do b; done");
+        assert_eq!(shown("for x in; do b; done"), "for x in ; do b; done");
+        // the words after `in` are not reserved
+        assert_eq!(shown("for do in do done\ndo b; done"), "for do in do done; do b; done");
+        assert_eq!(shown("for ((i=0;i<3;i++)); do b; done"), "for ((i=0; i<3; i++)); do b; done");
+        assert_eq!(shown("for ((;;))\ndo b; done"), "for ((; ; )); do b; done");
+    }
+
+    #[test]
+    fn unfinished_loops() {
+        for input in ["while a", "while a; do", "until a; do b", "for x", "for x in a", "for ((;;))"] {
+            assert!(is_incomplete(&tokens(input)), "{}", input);
+        }
+    }
+
+    #[test]
+    fn loop_syntax_errors() {
+        let error = |input| parse(input).unwrap_err().to_string();
+        assert_eq!(error("while a; done"), "syntax error near unexpected token `done'");
+        assert_eq!(error("for 1 in a; do b; done"), "`1': not a valid identifier");
+        assert_eq!(error("for x y; do b; done"), "syntax error near unexpected token `y'");
+        assert_eq!(error("do b; done"), "syntax error near unexpected token `do'");
+    }
+
+    #[test]
+    fn case_clauses() {
+        let list = parse("case $x in\n(a | b*) one;;\n c) two ;& d) three;;& *) four\nesac").unwrap();
+        let Stage::Compound(Compound::Case(clause), _) = &list.items[0].first.commands[0] else {
+            panic!("not a case clause: {:?}", list);
+        };
+        assert_eq!(clause.word, "$x");
+        let patterns: Vec<&[String]> = clause.items.iter().map(|item| &item.patterns[..]).collect();
+        assert_eq!(patterns, [&["a", "b*"][..], &["c"], &["d"], &["*"]]);
+        let ends: Vec<CaseEnd> = clause.items.iter().map(|item| item.end).collect();
+        assert_eq!(ends, [CaseEnd::Break, CaseEnd::FallThrough, CaseEnd::Continue, CaseEnd::Break]);
+        assert_eq!(
+            list.to_string(),
+            "case $x in a | b*) one;; c) two;& d) three;;& *) four;; esac"
+        );
+        // an item may have an empty list, and the last one no `;;`
+        assert_eq!(parse("case x in a) ;; esac").unwrap().to_string(), "case x in a) ;; esac");
+        assert_eq!(parse("case x in esac").unwrap().to_string(), "case x in esac");
+        // `esac` is a pattern where a pattern is expected
+        assert_eq!(parse("case x in (esac) a;; esac").unwrap().items.len(), 1);
+    }
+
+    #[test]
+    fn unfinished_case() {
+        for input in ["case x", "case x in", "case x in a)", "case x in a) b;;", "case x in a | "] {
+            assert!(is_incomplete(&tokens(input)), "{}", input);
+        }
+    }
+
+    #[test]
+    fn case_syntax_errors() {
+        let error = |input| parse(input).unwrap_err().to_string();
+        assert_eq!(error("case x y"), "syntax error near unexpected token `y'");
+        assert_eq!(error("case x in a b) c;; esac"), "syntax error near unexpected token `b'");
+        assert_eq!(error("case x in ) c;; esac"), "syntax error near unexpected token `)'");
+        assert_eq!(error("a ;; b"), "syntax error near unexpected token `;;'");
+        assert_eq!(error("esac"), "syntax error near unexpected token `esac'");
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+
+// What matching a text found already: whether the tokens of a pattern from
+// an index on match a part of the text
+type Memo = HashMap<(*const Pattern, usize, usize, usize), bool>;
+
+// Shell pattern matching, shared by parameter expansion, filename globbing and
+// `case`: `*`, `?`, bracket expressions like `[a-z]`, `[!0-9]` or
+// `[[:alpha:]]`, and backslash escapes making the next character literal.
+// Extended patterns add bash's extglob groups like `+(a|b)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    tokens: Vec<PatternToken>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PatternToken {
+    Literal(char),
+    AnyChar,
+    AnyString,
+    Class { negated: bool, items: Vec<ClassItem> },
+    // `?(..)`, `*(..)`, `+(..)`, `@(..)` or `!(..)` with its `|` separated patterns
+    Group { kind: char, alternatives: Vec<Pattern> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    Named(String),
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        Self::parse(pattern, false)
+    }
+
+    // A pattern that may use the extglob groups
+    pub fn extended(pattern: &str) -> Self {
+        Self::parse(pattern, true)
+    }
+
+    fn parse(pattern: &str, extglob: bool) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = vec![];
+        let mut i = 0;
+
+        while i < chars.len() {
+            match chars[i] {
+                kind @ ('?' | '*' | '+' | '@' | '!') if
+                    extglob && chars.get(i + 1) == Some(&'(')
+                =>
+                    match parse_group(&chars, i + 1) {
+                        Some((alternatives, next)) => {
+                            let alternatives = alternatives
+                                .iter()
+                                .map(|alternative| Self::parse(alternative, true))
+                                .collect();
+                            tokens.push(PatternToken::Group { kind, alternatives });
+                            i = next;
+                        }
+                        // without its closing parenthesis it is no group
+                        None => {
+                            tokens.push(match kind {
+                                '?' => PatternToken::AnyChar,
+                                '*' => PatternToken::AnyString,
+                                _ => PatternToken::Literal(kind),
+                            });
+                            i += 1;
+                        }
+                    }
+                '\\' if i + 1 < chars.len() => {
+                    tokens.push(PatternToken::Literal(chars[i + 1]));
+                    i += 2;
+                }
+                '?' => {
+                    tokens.push(PatternToken::AnyChar);
+                    i += 1;
+                }
+                '*' => {
+                    // consecutive stars match the same as a single one
+                    if tokens.last() != Some(&PatternToken::AnyString) {
+                        tokens.push(PatternToken::AnyString);
+                    }
+                    i += 1;
+                }
+                '[' =>
+                    match parse_class(&chars, i) {
+                        Some((token, next)) => {
+                            tokens.push(token);
+                            i = next;
+                        }
+                        // without its closing bracket `[` is an ordinary character
+                        None => {
+                            tokens.push(PatternToken::Literal('['));
+                            i += 1;
+                        }
+                    }
+                ch => {
+                    tokens.push(PatternToken::Literal(ch));
+                    i += 1;
+                }
+            }
+        }
+
+        Self { tokens }
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        self.matches_chars(&chars)
+    }
+
+    fn matches_chars(&self, chars: &[char]) -> bool {
+        self.match_at(0, chars, 0, chars.len(), &mut HashMap::new())
+    }
+
+    // Try the tokens from `t` on the text from `s` to `end`. The results are
+    // remembered for each pattern, token and part of the text, the patterns of
+    // groups included, so that several stars or repeated groups stay cheap.
+    fn match_at(&self, t: usize, chars: &[char], s: usize, end: usize, memo: &mut Memo) -> bool {
+        let key = (self as *const Pattern, t, s, end);
+        if let Some(&matched) = memo.get(&key) {
+            return matched;
+        }
+        let matched = match self.tokens.get(t) {
+            None => s == end,
+            Some(PatternToken::AnyString) =>
+                (s..=end).any(|next| self.match_at(t + 1, chars, next, end, memo)),
+            Some(PatternToken::Group { .. }) => self.group_at(t, chars, s, end, memo),
+            Some(token) =>
+                s < end &&
+                    token_matches(token, chars[s]) &&
+                    self.match_at(t + 1, chars, s + 1, end, memo),
+        };
+        memo.insert(key, matched);
+        matched
+    }
+
+    // The group at token `t` followed by the rest of the tokens. `*(..)` and
+    // `+(..)` match one of their alternatives and are then tried again at the
+    // same token, instead of trying every way to split the text.
+    fn group_at(&self, t: usize, chars: &[char], s: usize, end: usize, memo: &mut Memo) -> bool {
+        let Some(PatternToken::Group { kind, alternatives }) = self.tokens.get(t) else {
+            return false;
+        };
+        let one = |next: usize, memo: &mut Memo| {
+            alternatives.iter().any(|pattern| pattern.match_at(0, chars, s, next, memo))
+        };
+        match kind {
+            '?' =>
+                self.match_at(t + 1, chars, s, end, memo) ||
+                    (s..=end).any(|next| one(next, memo) && self.match_at(t + 1, chars, next, end, memo)),
+            '@' => (s..=end).any(|next| one(next, memo) && self.match_at(t + 1, chars, next, end, memo)),
+            '!' => (s..=end).any(|next| !one(next, memo) && self.match_at(t + 1, chars, next, end, memo)),
+            // each repetition is not empty, after the first one `+(..)` is `*(..)`
+            _ =>
+                (*kind == '*' && self.match_at(t + 1, chars, s, end, memo)) ||
+                    (s + 1..=end).any(|next| {
+                        one(next, memo) &&
+                            (self.match_at(t + 1, chars, next, end, memo) ||
+                                self.match_at(t, chars, next, end, memo))
+                    }),
+        }
+    }
+}
+
+fn token_matches(token: &PatternToken, ch: char) -> bool {
+    match token {
+        PatternToken::Literal(literal) => *literal == ch,
+        PatternToken::AnyChar | PatternToken::AnyString => true,
+        PatternToken::Class { negated, items } => {
+            let found = items.iter().any(|item| {
+                match item {
+                    ClassItem::Char(c) => *c == ch,
+                    ClassItem::Range(from, to) => *from <= ch && ch <= *to,
+                    ClassItem::Named(name) => named_class_matches(name, ch),
+                }
+            });
+            found != *negated
+        }
+        PatternToken::Group { .. } => false,
+    }
+}
+
+// Split the group opening with the `(` at `start` on its top level `|`,
+// returns the alternatives and the index after its closing `)`
+fn parse_group(chars: &[char], start: usize) -> Option<(Vec<String>, usize)> {
+    let mut alternatives = vec![];
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut i = start + 1;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        match ch {
+            '\\' if i + 1 < chars.len() => {
+                current.push(ch);
+                current.push(chars[i + 1]);
+                i += 1;
+            }
+            '(' => {
+                depth += 1;
+                current.push(ch);
+            }
+            ')' if depth == 0 => {
+                alternatives.push(current);
+                return Some((alternatives, i + 1));
+            }
+            ')' => {
+                depth -= 1;
+                current.push(ch);
+            }
+            '|' if depth == 0 => alternatives.push(std::mem::take(&mut current)),
+            _ => current.push(ch),
+        }
+        i += 1;
+    }
+
+    None
+}
+
+fn named_class_matches(name: &str, ch: char) -> bool {
+    match name {
+        "alpha" => ch.is_alphabetic(),
+        "digit" => ch.is_ascii_digit(),
+        "alnum" => ch.is_alphanumeric(),
+        "upper" => ch.is_uppercase(),
+        "lower" => ch.is_lowercase(),
+        "space" => ch.is_whitespace(),
+        "blank" => ch == ' ' || ch == '\t',
+        "punct" => ch.is_ascii_punctuation(),
+        "xdigit" => ch.is_ascii_hexdigit(),
+        "cntrl" => ch.is_control(),
+        "print" => !ch.is_control(),
+        "graph" => !ch.is_control() && !ch.is_whitespace(),
+        _ => false,
+    }
+}
+
+// Parse the bracket expression opening at `start`, returns the token and the
+// index after its closing `]`
+fn parse_class(chars: &[char], start: usize) -> Option<(PatternToken, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(chars.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut items = vec![];
+    let mut first = true;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        match ch {
+            // a `]` right after the opening bracket is part of the set
+            ']' if !first => {
+                return Some((PatternToken::Class { negated, items }, i + 1));
+            }
+            '[' if chars.get(i + 1) == Some(&':') => {
+                let rest: String = chars[i + 2..].iter().collect();
+                let end = rest.find(":]")?;
+                items.push(ClassItem::Named(rest[..end].to_string()));
+                i += 2 + rest[..end].chars().count() + 2;
+            }
+            _ => {
+                let (ch, len) = match ch {
+                    '\\' if i + 1 < chars.len() => (chars[i + 1], 2),
+                    _ => (ch, 1),
+                };
+                i += len;
+                match (chars.get(i), chars.get(i + 1)) {
+                    (Some('-'), Some(&to)) if to != ']' => {
+                        items.push(ClassItem::Range(ch, to));
+                        i += 2;
+                    }
+                    _ => items.push(ClassItem::Char(ch)),
+                }
+            }
+        }
+        first = false;
+    }
+
+    None
+}
+
+// Make every character of `text` match only itself
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for ch in text.chars() {
+        if matches!(ch, '*' | '?' | '[' | ']' | '\\' | '(' | ')' | '|') {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+// Whether the pattern has an unescaped `*`, `?` or `[`, or an extglob group
+// when `extglob` is set, otherwise it only matches the text it spells
+pub fn has_wildcards(pattern: &str, extglob: bool) -> bool {
+    let mut chars = pattern.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => {
+                return true;
+            }
+            '+' | '@' | '!' if extglob && chars.peek() == Some(&'(') => {
+                return true;
+            }
+            _ => (),
+        }
+    }
+    false
+}
+
+// The text a pattern without wildcards matches
+pub fn unescape(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => text.extend(chars.next()),
+            _ => text.push(ch),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::new(pattern).matches(text)
+    }
+
+    fn matches_extended(pattern: &str, text: &str) -> bool {
+        Pattern::extended(pattern).matches(text)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "main.rsx"));
+        assert!(matches("*", ""));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+    }
+
+    #[test]
+    fn bracket_expressions() {
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(matches("[^a-c]x", "dx"));
+        assert!(matches("[[:digit:]]*", "9lives"));
+        assert!(!matches("[[:alpha:]]", "1"));
+        // a `]` right after the opening bracket is part of the set
+        assert!(matches("[]x]", "]"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert_eq!(unescape(&escape("a*[b]?")), "a*[b]?");
+        assert!(matches(&escape("a*[b]?"), "a*[b]?"));
+    }
+
+    #[test]
+    fn extglob_groups() {
+        assert!(matches_extended("*(ab)", "abab"));
+        assert!(matches_extended("*(ab)", ""));
+        assert!(!matches_extended("*(ab)", "aba"));
+        assert!(!matches_extended("+(ab)", ""));
+        assert!(matches_extended("+(ab)", "ab"));
+        assert!(matches_extended("?(a|b)c", "c"));
+        assert!(!matches_extended("?(a|b)c", "abc"));
+        assert!(matches_extended("@(foo|bar).txt", "bar.txt"));
+        assert!(!matches_extended("!(*.c)", "main.c"));
+        assert!(matches_extended("!(*.c)", "main.h"));
+        assert!(matches_extended("!(a|b)", "ab"));
+        assert!(matches_extended("@(a|b)*(c)", "accc"));
+        // without extglob the group is plain characters
+        assert!(!matches("@(a|b)", "a"));
+    }
+
+    #[test]
+    fn nested_extglob_groups() {
+        assert!(matches_extended("*(a*(b)c)", "abbcac"));
+        assert!(!matches_extended("*(a*(b)c)", "abbca"));
+        assert!(matches_extended("+(a|*(b|c))d", "bcbd"));
+        assert!(matches_extended("x*(y+(z))w", "xyzzyzw"));
+        assert!(!matches_extended("x*(y+(z))w", "xyw"));
+    }
+
+    #[test]
+    fn long_subjects() {
+        // trying every way to split the text into repetitions would never end
+        let text = format!("{}b", "a".repeat(100));
+        assert!(!matches_extended("*(a|aa)", &text));
+        assert!(matches_extended("*(a|aa)b", &text));
+        assert!(!matches_extended("+(a|aa|*(a))c", &text));
+        assert!(!matches_extended("*(a*(a))", &text));
+        assert!(matches_extended("!(*(a|aa))", &text));
+    }
+
+    #[test]
+    fn wildcard_detection() {
+        assert!(has_wildcards("*.rs", false));
+        assert!(!has_wildcards("\\*.rs", false));
+        assert!(!has_wildcards("@(a|b)", false));
+        assert!(has_wildcards("@(a|b)", true));
+    }
+}
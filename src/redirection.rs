@@ -0,0 +1,120 @@
+use anyhow::anyhow;
+use std::fs::{ File, OpenOptions };
+use std::io::Write;
+use std::os::fd::{ AsFd, OwnedFd };
+use crate::errors::CrateResult;
+use crate::helpers::RedirectKind;
+
+// The standard streams of one command. `None` means the stream is inherited
+// from the shell itself.
+#[derive(Debug, Default)]
+pub struct Streams {
+    pub stdin: Option<OwnedFd>,
+    pub stdout: Option<OwnedFd>,
+    pub stderr: Option<OwnedFd>,
+}
+
+impl Streams {
+    // Apply one redirection on top of the current streams, later ones win
+    pub fn redirect(&mut self, fd: u32, kind: RedirectKind, target: &str) -> CrateResult<()> {
+        match kind {
+            RedirectKind::Input => {
+                let file = open_target(target, OpenOptions::new().read(true), "reading")?;
+                *self.slot(fd)? = Some(file.into());
+            }
+            RedirectKind::Output => {
+                let file = open_target(
+                    target,
+                    OpenOptions::new().write(true).create(true).truncate(true),
+                    "writing"
+                )?;
+                *self.slot(fd)? = Some(file.into());
+            }
+            RedirectKind::Append => {
+                let file = open_target(
+                    target,
+                    OpenOptions::new().append(true).create(true),
+                    "appending"
+                )?;
+                *self.slot(fd)? = Some(file.into());
+            }
+            RedirectKind::OutputAll | RedirectKind::AppendAll => {
+                let mut options = OpenOptions::new();
+                if kind == RedirectKind::AppendAll {
+                    options.append(true).create(true);
+                } else {
+                    options.write(true).create(true).truncate(true);
+                }
+                let file = open_target(target, &options, "writing")?;
+                self.stderr = Some(file.try_clone()?.into());
+                self.stdout = Some(file.into());
+            }
+            RedirectKind::HereDoc | RedirectKind::HereDocQuoted => {
+                *self.slot(fd)? = Some(feed_text(target.to_string())?);
+            }
+            RedirectKind::HereString => {
+                *self.slot(fd)? = Some(feed_text(format!("{}\n", target))?);
+            }
+            RedirectKind::Duplicate => {
+                let source = target
+                    .parse::<u32>()
+                    .map_err(|_| anyhow!("{}: ambiguous redirect", target))?;
+                let copy = self.duplicate(source)?;
+                *self.slot(fd)? = Some(copy);
+            }
+        }
+        Ok(())
+    }
+
+    fn slot(&mut self, fd: u32) -> CrateResult<&mut Option<OwnedFd>> {
+        match fd {
+            0 => Ok(&mut self.stdin),
+            1 => Ok(&mut self.stdout),
+            2 => Ok(&mut self.stderr),
+            _ => Err(anyhow!("{}: bad file descriptor", fd)),
+        }
+    }
+
+    // A copy of one of the streams, falling back to the shell's own descriptor
+    fn duplicate(&mut self, fd: u32) -> CrateResult<OwnedFd> {
+        let copy = match self.slot(fd)? {
+            Some(stream) => stream.try_clone()?,
+            None =>
+                match fd {
+                    0 => std::io::stdin().as_fd().try_clone_to_owned()?,
+                    1 => std::io::stdout().as_fd().try_clone_to_owned()?,
+                    _ => std::io::stderr().as_fd().try_clone_to_owned()?,
+                }
+        };
+        Ok(copy)
+    }
+}
+
+// A pipe whose read end delivers `text`, written from its own thread so that
+// bodies larger than the pipe buffer do not block the shell
+fn feed_text(text: String) -> CrateResult<OwnedFd> {
+    let (reader, mut writer) = std::io::pipe()?;
+    std::thread::spawn(move || {
+        let _ = writer.write_all(text.as_bytes());
+    });
+    Ok(reader.into())
+}
+
+fn open_target(target: &str, options: &OpenOptions, purpose: &str) -> CrateResult<File> {
+    options.open(target).map_err(|e| anyhow!("cannot open '{}' for {}: {}", target, purpose, e))
+}
+
+pub fn write_stream(stream: &OwnedFd, text: &str) -> std::io::Result<()> {
+    let mut file = File::from(stream.try_clone()?);
+    file.write_all(text.as_bytes())
+}
+
+// Report an error on the given stream, or on the shell's own stderr
+pub fn report_error(stderr: Option<&OwnedFd>, error: &str) {
+    match stderr {
+        Some(stream) => {
+            let _ = write_stream(stream, &format!("Error: {}\n", error));
+        }
+        None => eprintln!("Error: {}", error),
+    }
+}
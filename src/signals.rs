@@ -0,0 +1,82 @@
+use std::sync::atomic::{ AtomicI32, Ordering };
+use std::sync::{ Arc, Mutex };
+use tokio::signal::unix::{ signal, SignalKind };
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use crate::errors::CrateResult;
+
+// The keyboard signals of an interactive shell. The shell never dies or stops
+// because of them: Ctrl-C aborts the input line or the running command, and
+// what the shell receives while a job owns the terminal is passed on to it.
+#[derive(Debug, Default)]
+pub struct Signals {
+    // process group of the foreground job, 0 when none runs in its own group
+    foreground: AtomicI32,
+    // cancelled by Ctrl-C, long running builtins stop once they notice
+    cancel: Mutex<CancellationToken>,
+    interrupt: Notify,
+}
+
+impl Signals {
+    // Catch SIGINT, SIGQUIT and SIGTSTP for the rest of the process lifetime
+    pub fn listen() -> CrateResult<Arc<Self>> {
+        let signals = Arc::new(Self::default());
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut quit = signal(SignalKind::quit())?;
+        let mut stop = signal(SignalKind::from_raw(libc::SIGTSTP))?;
+        let state = signals.clone();
+        tokio::spawn(async move {
+            loop {
+                let received = tokio::select! {
+                    _ = interrupt.recv() => libc::SIGINT,
+                    _ = quit.recv() => libc::SIGQUIT,
+                    _ = stop.recv() => libc::SIGTSTP,
+                };
+                state.forward(received);
+                if received == libc::SIGINT {
+                    state.interrupt();
+                }
+            }
+        });
+        Ok(signals)
+    }
+
+    // Without job control the children share the group of the shell and got
+    // the signal from the terminal already, so only separate groups need it
+    fn forward(&self, signal: i32) {
+        let pgid = self.foreground.load(Ordering::SeqCst);
+        if pgid > 0 {
+            unsafe {
+                libc::kill(-pgid, signal);
+            }
+        }
+    }
+
+    pub fn set_foreground(&self, pgid: i32) {
+        self.foreground.store(pgid, Ordering::SeqCst);
+    }
+
+    // Record an interrupt, whether the shell caught it or saw a job die of it
+    pub fn interrupt(&self) {
+        self.token().cancel();
+        self.interrupt.notify_waiters();
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.token().is_cancelled()
+    }
+
+    // Start over with a token that is not cancelled yet
+    pub fn clear(&self) {
+        *self.cancel.lock().unwrap() = CancellationToken::new();
+    }
+
+    pub fn token(&self) -> CancellationToken {
+        self.cancel.lock().unwrap().clone()
+    }
+
+    // Resolve on the next Ctrl-C, earlier ones are not remembered
+    pub async fn interrupted(&self) {
+        self.interrupt.notified().await;
+    }
+}
@@ -0,0 +1,583 @@
+use std::collections::BTreeMap;
+use anyhow::anyhow;
+use crate::arithmetic::evaluate;
+use crate::errors::CrateResult;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variable {
+    // None for a name that was exported before getting a value
+    pub value: Option<Value>,
+    pub exported: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(String),
+    // indexed arrays may have gaps, like after `arr[5]=x`
+    Indexed(BTreeMap<usize, String>),
+    Associative(BTreeMap<String, String>),
+}
+
+// Which element of an array a subscript designates
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subscript {
+    // negative indexes count from the end
+    Index(i64),
+    Key(String),
+}
+
+impl Value {
+    fn scalar(&self) -> Option<&str> {
+        match self {
+            Value::Scalar(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// The shell variables, seeded from the environment of the process. The
+// exported ones make the environment of the programs started by the shell,
+// the one of the shell process itself is never changed.
+#[derive(Debug, Clone, Default)]
+pub struct Variables {
+    vars: BTreeMap<String, Variable>,
+}
+
+impl Variables {
+    pub fn from_env() -> Self {
+        let vars = std::env
+            ::vars_os()
+            .map(|(name, value)| {
+                let variable = Variable {
+                    value: Some(Value::Scalar(value.to_string_lossy().to_string())),
+                    exported: true,
+                };
+                (name.to_string_lossy().to_string(), variable)
+            })
+            .collect();
+        Self { vars }
+    }
+
+    // The value of a variable, element 0 for an array
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match self.vars.get(name)?.value.as_ref()? {
+            Value::Scalar(value) => Some(value),
+            Value::Indexed(elements) => elements.get(&0).map(String::as_str),
+            Value::Associative(elements) => elements.get("0").map(String::as_str),
+        }
+    }
+
+    // Assigning to an array without a subscript assigns its element 0
+    pub fn set(&mut self, name: &str, value: String) {
+        let var = self.vars.entry(name.to_string()).or_default();
+        match &mut var.value {
+            Some(Value::Indexed(elements)) => {
+                elements.insert(0, value);
+            }
+            Some(Value::Associative(elements)) => {
+                elements.insert("0".to_string(), value);
+            }
+            _ => {
+                var.value = Some(Value::Scalar(value));
+            }
+        }
+    }
+
+    // Replace the whole value, like `arr=(a b c)` does
+    pub fn set_value(&mut self, name: &str, value: Value) {
+        if let Value::Scalar(value) = value {
+            self.vars.remove(name);
+            return self.set(name, value);
+        }
+        self.vars.entry(name.to_string()).or_default().value = Some(value);
+    }
+
+    pub fn value(&self, name: &str) -> Option<&Value> {
+        self.vars.get(name)?.value.as_ref()
+    }
+
+    pub fn is_associative(&self, name: &str) -> bool {
+        matches!(self.value(name), Some(Value::Associative(_)))
+    }
+
+    // `declare -a` and `declare -A`: make the variable an array, a scalar
+    // value becomes its element 0
+    pub fn declare_array(&mut self, name: &str, associative: bool) -> CrateResult<()> {
+        let value = match (self.vars.get(name).and_then(|var| var.value.clone()), associative) {
+            (None, false) => Value::Indexed(BTreeMap::new()),
+            (None, true) => Value::Associative(BTreeMap::new()),
+            (Some(Value::Scalar(value)), false) => Value::Indexed(BTreeMap::from([(0, value)])),
+            (Some(Value::Scalar(value)), true) =>
+                Value::Associative(BTreeMap::from([("0".to_string(), value)])),
+            (Some(Value::Indexed(_)), true) => {
+                return Err(anyhow!("{}: cannot convert indexed to associative array", name));
+            }
+            (Some(Value::Associative(_)), false) => {
+                return Err(anyhow!("{}: cannot convert associative to indexed array", name));
+            }
+            (Some(value), _) => value,
+        };
+        self.set_value(name, value);
+        Ok(())
+    }
+
+    // Resolve an expanded subscript: the key of an associative array, or an
+    // arithmetic expression giving the index
+    pub fn subscript(&mut self, name: &str, subscript: &str) -> CrateResult<Subscript> {
+        match self.is_associative(name) {
+            true => Ok(Subscript::Key(subscript.to_string())),
+            false => Ok(Subscript::Index(evaluate(subscript, self)?)),
+        }
+    }
+
+    pub fn element(&self, name: &str, subscript: &Subscript) -> Option<&str> {
+        match (self.value(name)?, subscript) {
+            (Value::Associative(elements), Subscript::Key(key)) => elements.get(key),
+            (value, Subscript::Index(index)) => {
+                let index = resolve_index(value, *index)?;
+                match value {
+                    Value::Scalar(value) if index == 0 => {
+                        return Some(value);
+                    }
+                    Value::Indexed(elements) => elements.get(&index),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }.map(String::as_str)
+    }
+
+    pub fn set_element(&mut self, name: &str, subscript: &Subscript, value: String) -> CrateResult<()> {
+        let current = self.vars.get(name).and_then(|var| var.value.clone());
+        let value = match (current, subscript) {
+            (Some(Value::Associative(mut elements)), Subscript::Key(key)) => {
+                elements.insert(key.clone(), value);
+                Value::Associative(elements)
+            }
+            (current, Subscript::Index(index)) => {
+                let mut elements = match current {
+                    Some(Value::Indexed(elements)) => elements,
+                    Some(Value::Scalar(scalar)) => BTreeMap::from([(0, scalar)]),
+                    _ => BTreeMap::new(),
+                };
+                let array = Value::Indexed(elements.clone());
+                let Some(index) = resolve_index(&array, *index) else {
+                    return Err(anyhow!("{}[{}]: bad array subscript", name, index));
+                };
+                elements.insert(index, value);
+                Value::Indexed(elements)
+            }
+            (_, Subscript::Key(key)) => {
+                return Err(anyhow!("{}[{}]: bad array subscript", name, key));
+            }
+        };
+        self.set_value(name, value);
+        Ok(())
+    }
+
+    pub fn unset_element(&mut self, name: &str, subscript: &Subscript) {
+        let Some(value) = self.vars.get_mut(name).and_then(|var| var.value.as_mut()) else {
+            return;
+        };
+        match (value, subscript) {
+            (Value::Associative(elements), Subscript::Key(key)) => {
+                elements.remove(key);
+            }
+            (value @ Value::Indexed(_), Subscript::Index(index)) => {
+                if let Some(index) = resolve_index(value, *index) {
+                    if let Value::Indexed(elements) = value {
+                        elements.remove(&index);
+                    }
+                }
+            }
+            (Value::Scalar(_), Subscript::Index(0)) => self.unset(name),
+            _ => (),
+        }
+    }
+
+    // The elements of `${name[@]}` in order, a scalar is an array of one
+    pub fn values(&self, name: &str) -> Vec<String> {
+        match self.value(name) {
+            None => vec![],
+            Some(Value::Scalar(value)) => vec![value.clone()],
+            Some(Value::Indexed(elements)) => elements.values().cloned().collect(),
+            Some(Value::Associative(elements)) => elements.values().cloned().collect(),
+        }
+    }
+
+    // The subscripts of `${!name[@]}`
+    pub fn keys(&self, name: &str) -> Vec<String> {
+        match self.value(name) {
+            None => vec![],
+            Some(Value::Scalar(_)) => vec!["0".to_string()],
+            Some(Value::Indexed(elements)) => elements.keys().map(|key| key.to_string()).collect(),
+            Some(Value::Associative(elements)) => elements.keys().cloned().collect(),
+        }
+    }
+
+    pub fn export(&mut self, name: &str) {
+        self.vars.entry(name.to_string()).or_default().exported = true;
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.vars.remove(name);
+    }
+
+    // Give the variables the values of a prefix assignment like `FOO=1 cmd`,
+    // exported for the duration of the command. Returns what `restore` needs.
+    pub fn assign_temporarily(
+        &mut self,
+        assignments: &[(String, String)]
+    ) -> Vec<(String, Option<Variable>)> {
+        let mut saved = vec![];
+        for (name, value) in assignments {
+            saved.push((name.clone(), self.vars.get(name).cloned()));
+            self.set(name, value.clone());
+            self.export(name);
+        }
+        saved
+    }
+
+    pub fn restore(&mut self, saved: Vec<(String, Option<Variable>)>) {
+        // in reverse, a name assigned twice gets its very first value back
+        for (name, var) in saved.into_iter().rev() {
+            self.unset(&name);
+            if let Some(var) = var {
+                self.vars.insert(name, var);
+            }
+        }
+    }
+
+    // `NAME=value` lines sorted by name, values quoted so they can be read back
+    pub fn listing(&self, exported_only: bool, prefix: &str) -> String {
+        let mut res = String::new();
+        for (name, var) in &self.vars {
+            if exported_only && !var.exported {
+                continue;
+            }
+            match &var.value {
+                Some(value) => res.push_str(&format!("{}{}={}\n", prefix, name, format_value(value))),
+                None if !prefix.is_empty() => res.push_str(&format!("{}{}\n", prefix, name)),
+                None => (),
+            }
+        }
+        res
+    }
+
+    // How `declare -p` shows a variable, like `declare -a arr=([0]=a [1]=b)`
+    pub fn declaration(&self, name: &str) -> Option<String> {
+        let var = self.vars.get(name)?;
+        let mut flags = match var.value {
+            Some(Value::Indexed(_)) => "a".to_string(),
+            Some(Value::Associative(_)) => "A".to_string(),
+            _ => String::new(),
+        };
+        if var.exported {
+            flags.push('x');
+        }
+        if flags.is_empty() {
+            flags.push('-');
+        }
+        Some(match &var.value {
+            Some(value) => format!("declare -{} {}={}\n", flags, name, format_value(value)),
+            None => format!("declare -{} {}\n", flags, name),
+        })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.vars.keys().cloned().collect()
+    }
+
+    // The environment of a program: the exported variables with a value,
+    // extra assignments on top
+    pub fn exported(&self, assignments: &[(String, String)]) -> BTreeMap<String, String> {
+        let mut env: BTreeMap<String, String> = self.vars
+            .iter()
+            .filter_map(|(name, var)| {
+                match (var.exported, var.value.as_ref().and_then(Value::scalar)) {
+                    (true, Some(value)) => Some((name.clone(), value.to_string())),
+                    _ => None,
+                }
+            })
+            .collect();
+        env.extend(assignments.iter().cloned());
+        env
+    }
+
+    // The environment as `env` shows it
+    pub fn environment(&self, assignments: &[(String, String)]) -> String {
+        self.exported(assignments)
+            .iter()
+            .map(|(name, value)| format!("{}={}\n", name, value))
+            .collect()
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_') &&
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Split `NAME=value` into its name and (still raw) value
+pub fn split_assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    if is_valid_name(name) { Some((name, value)) } else { None }
+}
+
+// A `NAME=value`, `NAME+=value` or `NAME[subscript]=value` assignment word,
+// its parts still raw
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Assignment<'a> {
+    pub name: &'a str,
+    pub subscript: Option<&'a str>,
+    pub append: bool,
+    pub value: &'a str,
+}
+
+impl Assignment<'_> {
+    // `NAME=(...)` assigns a whole array
+    pub fn compound(&self) -> Option<&str> {
+        match self.subscript {
+            None => self.value.strip_prefix('(')?.strip_suffix(')'),
+            Some(_) => None,
+        }
+    }
+}
+
+pub fn parse_assignment(word: &str) -> Option<Assignment<'_>> {
+    let name_len = word
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(word.len());
+    let name = &word[..name_len];
+    if !is_valid_name(name) {
+        return None;
+    }
+    let mut rest = &word[name_len..];
+    let mut subscript = None;
+    if rest.starts_with('[') {
+        let end = subscript_end(rest)?;
+        subscript = Some(&rest[1..end]);
+        rest = &rest[end + 1..];
+    }
+    let (append, value) = match rest.strip_prefix("+=") {
+        Some(value) => (true, value),
+        None => (false, rest.strip_prefix('=')?),
+    };
+    Some(Assignment { name, subscript, append, value })
+}
+
+// Split the `[subscript]=value` element of an array assignment
+pub fn split_element(word: &str) -> Option<(&str, &str)> {
+    if !word.starts_with('[') {
+        return None;
+    }
+    let end = subscript_end(word)?;
+    Some((&word[1..end], word[end + 1..].strip_prefix('=')?))
+}
+
+// Split `NAME[subscript]` into the name and the subscript
+pub fn split_subscript(word: &str) -> Option<(&str, &str)> {
+    let (name, _) = word.split_once('[')?;
+    let rest = &word[name.len()..];
+    let end = subscript_end(rest)?;
+    if !is_valid_name(name) || end + 1 != rest.len() {
+        return None;
+    }
+    Some((name, &rest[1..end]))
+}
+
+// The index of the `]` closing the `[` the text starts with
+fn subscript_end(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, ch) in text.char_indices() {
+        match ch {
+            '[' => depth += 1,
+            ']' if depth == 1 => {
+                return Some(idx);
+            }
+            ']' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+// The index an array element is stored at, negative ones count from the end
+fn resolve_index(value: &Value, index: i64) -> Option<usize> {
+    if index >= 0 {
+        return Some(index as usize);
+    }
+    let len = match value {
+        Value::Indexed(elements) => elements.keys().next_back().map_or(0, |last| last + 1),
+        _ => 1,
+    };
+    usize::try_from(len as i64 + index).ok()
+}
+
+// A value as `set` shows it, arrays like `([0]=a [1]=b)`
+fn format_value(value: &Value) -> String {
+    let elements: Vec<String> = match value {
+        Value::Scalar(value) => {
+            return quote(value);
+        }
+        Value::Indexed(elements) =>
+            elements
+                .iter()
+                .map(|(index, value)| format!("[{}]={}", index, quote(value)))
+                .collect(),
+        Value::Associative(elements) =>
+            elements
+                .iter()
+                .map(|(key, value)| format!("[{}]={}", quote(key), quote(value)))
+                .collect(),
+    };
+    format!("({})", elements.join(" "))
+}
+
+fn quote(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:,@%+=".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_unset() {
+        let mut vars = Variables::default();
+        assert_eq!(vars.get("A"), None);
+        vars.set("A", "1".to_string());
+        assert_eq!(vars.get("A"), Some("1"));
+        vars.unset("A");
+        assert_eq!(vars.get("A"), None);
+    }
+
+    #[test]
+    fn temporary_assignments() {
+        let mut vars = Variables::default();
+        vars.set("OWN_SHELL_TEST_KEPT", "old".to_string());
+        let saved = vars.assign_temporarily(
+            &[
+                ("OWN_SHELL_TEST_KEPT".to_string(), "new".to_string()),
+                ("OWN_SHELL_TEST_GONE".to_string(), "1".to_string()),
+                ("OWN_SHELL_TEST_KEPT".to_string(), "newer".to_string()),
+            ]
+        );
+        assert_eq!(vars.get("OWN_SHELL_TEST_KEPT"), Some("newer"));
+        assert!(vars.listing(true, "").contains("OWN_SHELL_TEST_GONE=1\n"));
+        vars.restore(saved);
+        assert_eq!(vars.get("OWN_SHELL_TEST_KEPT"), Some("old"));
+        assert_eq!(vars.get("OWN_SHELL_TEST_GONE"), None);
+        assert_eq!(vars.listing(true, ""), "");
+    }
+
+    #[test]
+    fn listings() {
+        let mut vars = Variables::default();
+        vars.set("B", "two words".to_string());
+        vars.set("A", "it's".to_string());
+        vars.export("C");
+        assert_eq!(vars.listing(false, ""), "A='it'\\''s'\nB='two words'\n");
+        assert_eq!(vars.listing(true, "export "), "export C\n");
+        assert_eq!(vars.environment(&[("D".to_string(), "4".to_string())]), "D=4\n");
+    }
+
+    #[test]
+    fn names() {
+        assert!(is_valid_name("_a1"));
+        assert!(!is_valid_name("1a"));
+        assert!(!is_valid_name(""));
+        assert_eq!(split_assignment("A=b=c"), Some(("A", "b=c")));
+        assert_eq!(split_assignment("A-B=c"), None);
+        assert_eq!(split_assignment("plain"), None);
+    }
+
+    #[test]
+    fn indexed_arrays() {
+        let mut vars = Variables::default();
+        vars.set_element("a", &Subscript::Index(2), "c".to_string()).unwrap();
+        vars.set_element("a", &Subscript::Index(0), "x".to_string()).unwrap();
+        assert_eq!(vars.get("a"), Some("x"));
+        assert_eq!(vars.values("a"), ["x", "c"]);
+        assert_eq!(vars.keys("a"), ["0", "2"]);
+        // negative indexes count from the last element
+        assert_eq!(vars.element("a", &Subscript::Index(-1)), Some("c"));
+        assert_eq!(vars.element("a", &Subscript::Index(-3)), Some("x"));
+        assert_eq!(vars.element("a", &Subscript::Index(-4)), None);
+        let err = vars.set_element("a", &Subscript::Index(-4), "y".to_string()).unwrap_err();
+        assert_eq!(err.to_string(), "a[-4]: bad array subscript");
+        vars.unset_element("a", &Subscript::Index(-1));
+        assert_eq!(vars.declaration("a").unwrap(), "declare -a a=([0]=x)\n");
+        // a scalar is an array of one element
+        vars.set("s", "v".to_string());
+        assert_eq!(vars.element("s", &Subscript::Index(0)), Some("v"));
+        assert_eq!(vars.values("s"), ["v"]);
+    }
+
+    #[test]
+    fn associative_arrays() {
+        let mut vars = Variables::default();
+        vars.set("m", "zero".to_string());
+        vars.declare_array("m", true).unwrap();
+        let key = vars.subscript("m", "1 + 1").unwrap();
+        assert_eq!(key, Subscript::Key("1 + 1".to_string()));
+        vars.set_element("m", &key, "two words".to_string()).unwrap();
+        assert_eq!(vars.get("m"), Some("zero"));
+        assert_eq!(vars.keys("m"), ["0", "1 + 1"]);
+        assert_eq!(
+            vars.declaration("m").unwrap(),
+            "declare -A m=([0]=zero ['1 + 1']='two words')\n"
+        );
+        let err = vars.declare_array("m", false).unwrap_err();
+        assert_eq!(err.to_string(), "m: cannot convert associative to indexed array");
+        vars.unset_element("m", &Subscript::Key("0".to_string()));
+        assert_eq!(vars.values("m"), ["two words"]);
+    }
+
+    #[test]
+    fn indexes_are_arithmetic() {
+        let mut vars = Variables::default();
+        vars.set("i", "2".to_string());
+        vars.declare_array("a", false).unwrap();
+        assert_eq!(vars.subscript("a", "i * 2").unwrap(), Subscript::Index(4));
+        assert!(vars.subscript("a", "1 +").is_err());
+    }
+
+    #[test]
+    fn assignment_words() {
+        let assignment = parse_assignment("a[i+1]+=x=y").unwrap();
+        assert_eq!(assignment.name, "a");
+        assert_eq!(assignment.subscript, Some("i+1"));
+        assert!(assignment.append);
+        assert_eq!(assignment.value, "x=y");
+        assert_eq!(parse_assignment("a=(1 2)").unwrap().compound(), Some("1 2"));
+        assert_eq!(parse_assignment("a[0]=(1 2)").unwrap().compound(), None);
+        assert_eq!(parse_assignment("a[0=x"), None);
+        assert_eq!(parse_assignment("1a=x"), None);
+        assert_eq!(split_element("[k]=v"), Some(("k", "v")));
+        assert_eq!(split_subscript("a[b[1]]"), Some(("a", "b[1]")));
+        assert_eq!(split_subscript("a[1]x"), None);
+    }
+
+    #[test]
+    fn exported_values() {
+        let mut vars = Variables::default();
+        vars.set("OWN_SHELL_TEST_EXPORTED", "1".to_string());
+        vars.export("OWN_SHELL_TEST_EXPORTED");
+        vars.set("local", "2".to_string());
+        vars.declare_array("arr", false).unwrap();
+        vars.export("arr");
+        let assignments = [("local".to_string(), "3".to_string())];
+        let env = vars.exported(&assignments);
+        let env: Vec<(&str, &str)> = env.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(env, [("OWN_SHELL_TEST_EXPORTED", "1"), ("local", "3")]);
+        // the process environment is left alone
+        assert!(std::env::var_os("OWN_SHELL_TEST_EXPORTED").is_none());
+    }
+}